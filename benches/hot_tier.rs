@@ -0,0 +1,60 @@
+//! Reading a cache entry from the filesystem store, with and without the hot tier in front.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use pt_cracker::cache::{
+    HotTier, Peer, TorrentCache,
+    store::{CacheStore as _, FilesystemStore},
+};
+
+const INFO_HASH: &str = "%12%34%56%78%9A%BC%DE%F0%12%34%56%78%9A%BC%DE%F0%12%34%56%78";
+
+/// A realistic entry with a few hundred peers, written to a fresh directory.
+fn setup(name: &str) -> (tokio::runtime::Runtime, PathBuf, TorrentCache) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let root = std::env::temp_dir().join(format!("pt_cracker_bench_{name}"));
+    let mut cache = TorrentCache {
+        size: 1 << 30,
+        ..Default::default()
+    };
+    let expire = SystemTime::now() + Duration::from_secs(3600);
+    cache
+        .trackers
+        .insert("tracker.example.com".to_string(), expire);
+    for i in 0..200u16 {
+        let addr = ([10, 0, (i >> 8) as u8, i as u8], 6881 + i).into();
+        cache.peers_addr.insert(addr, expire);
+        cache.peers_time.insert(Peer { expire, addr });
+    }
+    (runtime, root, cache)
+}
+
+fn get_from_filesystem(c: &mut Criterion) {
+    let (runtime, root, cache) = setup("filesystem");
+    let store = FilesystemStore::new(root.clone());
+    runtime.block_on(store.put(INFO_HASH, &cache)).unwrap();
+    c.bench_function("get_from_filesystem", |b| {
+        b.iter(|| runtime.block_on(store.get(INFO_HASH)).unwrap())
+    });
+    let _ = std::fs::remove_dir_all(root);
+}
+
+fn get_from_hot_tier(c: &mut Criterion) {
+    let (runtime, root, cache) = setup("hot_tier");
+    let store = HotTier::new(FilesystemStore::new(root.clone()), 16);
+    runtime.block_on(store.put(INFO_HASH, &cache)).unwrap();
+    c.bench_function("get_from_hot_tier", |b| {
+        b.iter(|| runtime.block_on(store.get(INFO_HASH)).unwrap())
+    });
+    let _ = std::fs::remove_dir_all(root);
+}
+
+criterion_group!(benches, get_from_filesystem, get_from_hot_tier);
+criterion_main!(benches);
@@ -0,0 +1,491 @@
+//! The admin API under `/admin/api`, for inspecting and managing the cache of a running instance.
+//!
+//! Requests must carry the configured `admin_token` as a bearer token, and without one configured
+//! the API does not exist at all. Info hashes in paths are 40 hex digits, and times are Unix
+//! timestamps in seconds.
+//!
+//! The dashboard at `/admin` is a static page which asks for the token and then polls the API.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use pt_cracker::{
+    cache::{
+        TorrentCache, blacklist_entry, circuit_states, hottest_entries, list_blacklist,
+        list_entries, purge_entry, read_entry, refresh_entry, unblacklist_entry,
+    },
+    config::config,
+    health::liveness,
+    logging::recent_errors,
+    metainfo::{parse_info_hash, to_hex},
+    metrics::{counter_values, metrics},
+    tracker::CircuitState,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tracing::error;
+use warp::{Filter, Rejection, Reply, http::StatusCode, reply::Response};
+
+/// Entries listed at once unless asked otherwise.
+const DEFAULT_LIMIT: usize = 1000;
+/// Hottest torrents listed unless asked otherwise.
+const DEFAULT_HOTTEST: usize = 10;
+
+const DASHBOARD: &str = include_str!("../../../www/static/admin.html");
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// An `info_hash` in a path. Anything else does not match the route.
+struct InfoHash(Vec<u8>);
+
+impl FromStr for InfoHash {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        parse_info_hash(value).map(Self)
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct HottestQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RefreshQuery {
+    /// The origin tracker to announce to, passkey included. Without it, the cache is only expired.
+    tracker_url: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TrackerSummary {
+    tracker: String,
+    expires: u64,
+    announced: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct EntrySummary {
+    info_hash: String,
+    size: u64,
+    peers: usize,
+    clients: usize,
+    downloaded: u64,
+    expires: u64,
+    trackers: Vec<TrackerSummary>,
+}
+
+#[derive(Serialize)]
+struct PeerDetail {
+    addr: SocketAddr,
+    expires: u64,
+    trackers: Vec<String>,
+    first_seen: Option<u64>,
+    last_seen: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct EntryDetail {
+    #[serde(flatten)]
+    summary: EntrySummary,
+    peer_list: Vec<PeerDetail>,
+}
+
+#[derive(Serialize)]
+struct HotEntry {
+    info_hash: String,
+    /// Announces since startup.
+    requests: u64,
+    /// `None` if the torrent is not cached, e.g. because its refreshes failed.
+    peers: Option<usize>,
+    expires: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CircuitSummary {
+    tracker: String,
+    /// `closed`, `open` or `half_open`.
+    state: &'static str,
+    /// Consecutive failures of a closed circuit.
+    failures: Option<u32>,
+    /// Seconds until an open circuit lets a probe through.
+    retry_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ErrorSummary {
+    time: u64,
+    level: String,
+    message: String,
+}
+
+fn unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn summarize(info_hash: &[u8], cache: &TorrentCache) -> EntrySummary {
+    let mut trackers: Vec<_> = cache
+        .trackers
+        .iter()
+        .map(|(tracker, &expiration)| TrackerSummary {
+            tracker: tracker.clone(),
+            expires: unix(expiration),
+            announced: cache.announced.get(tracker).copied().map(unix),
+        })
+        .collect();
+    trackers.sort_by(|a, b| a.tracker.cmp(&b.tracker));
+    EntrySummary {
+        info_hash: to_hex(info_hash),
+        size: cache.size,
+        peers: cache.peers_addr.len(),
+        clients: cache.clients.len(),
+        downloaded: cache.downloaded,
+        expires: unix(cache.expiration()),
+        trackers,
+    }
+}
+
+fn detail(info_hash: &[u8], cache: &TorrentCache) -> EntryDetail {
+    EntryDetail {
+        summary: summarize(info_hash, cache),
+        peer_list: cache
+            .peers_time
+            .iter()
+            .map(|peer| {
+                let provenance = cache.peer_sources.get(&peer.addr);
+                PeerDetail {
+                    addr: peer.addr,
+                    expires: unix(peer.expire),
+                    trackers: provenance
+                        .map(|x| x.trackers.iter().cloned().collect())
+                        .unwrap_or_default(),
+                    first_seen: provenance.map(|x| unix(x.first_seen)),
+                    last_seen: provenance.map(|x| unix(x.last_seen)),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn json(value: &impl serde::Serialize, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(value), status).into_response()
+}
+
+fn failure(status: StatusCode, message: &str) -> Response {
+    json(&serde_json::json!({ "error": message }), status)
+}
+
+fn not_cached() -> Response {
+    failure(StatusCode::NOT_FOUND, "torrent is not cached")
+}
+
+fn respond(result: Result<Response>) -> Response {
+    result.unwrap_or_else(|e| {
+        error!("admin request failed: {e:#}");
+        failure(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e:#}"))
+    })
+}
+
+/// Passes requests carrying the admin token, and rejects everything if there is none.
+fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|header: Option<String>| async move {
+            let Some(token) = &config().server.admin_token else {
+                return Err(warp::reject::not_found());
+            };
+            let given = header.as_deref().and_then(|x| x.strip_prefix("Bearer "));
+            // Comparing digests takes as long however much of the token is right.
+            if given.is_some_and(|x| Sha256::digest(x) == Sha256::digest(token)) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(Unauthorized))
+            }
+        })
+        .untuple_one()
+}
+
+async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let mut response = failure(StatusCode::UNAUTHORIZED, "missing or invalid admin token");
+        response.headers_mut().insert(
+            "WWW-Authenticate",
+            warp::http::HeaderValue::from_static("Bearer"),
+        );
+        Ok(response)
+    } else {
+        Err(rejection)
+    }
+}
+
+async fn list_torrents(query: ListQuery) -> Result<Response> {
+    // Sorted, so that pages are stable.
+    let mut info_hashes = list_entries().await?;
+    info_hashes.sort();
+    let mut result = Vec::new();
+    for info_hash in info_hashes
+        .iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(DEFAULT_LIMIT))
+    {
+        if let Some(cache) = read_entry(info_hash).await? {
+            result.push(summarize(info_hash, &cache));
+        }
+    }
+    Ok(json(&result, StatusCode::OK))
+}
+
+async fn show_torrent(InfoHash(info_hash): InfoHash) -> Result<Response> {
+    Ok(match read_entry(&info_hash).await? {
+        Some(cache) => json(&detail(&info_hash, &cache), StatusCode::OK),
+        None => not_cached(),
+    })
+}
+
+async fn refresh_torrent(InfoHash(info_hash): InfoHash, query: RefreshQuery) -> Result<Response> {
+    Ok(match refresh_entry(&info_hash, query.tracker_url).await? {
+        Some(cache) => json(&summarize(&info_hash, &cache), StatusCode::OK),
+        None => not_cached(),
+    })
+}
+
+async fn purge_torrent(InfoHash(info_hash): InfoHash) -> Result<Response> {
+    Ok(if purge_entry(&info_hash).await? {
+        json(&serde_json::json!({ "purged": 1 }), StatusCode::OK)
+    } else {
+        not_cached()
+    })
+}
+
+async fn purge_all() -> Result<Response> {
+    let mut purged = 0;
+    for info_hash in list_entries().await? {
+        if purge_entry(&info_hash).await? {
+            purged += 1;
+        }
+    }
+    Ok(json(
+        &serde_json::json!({ "purged": purged }),
+        StatusCode::OK,
+    ))
+}
+
+async fn show_blacklist() -> Result<Response> {
+    let blacklist: Vec<_> = list_blacklist().iter().map(|x| to_hex(x)).collect();
+    Ok(json(&blacklist, StatusCode::OK))
+}
+
+async fn add_to_blacklist(InfoHash(info_hash): InfoHash) -> Result<Response> {
+    let added = blacklist_entry(&info_hash).await?;
+    Ok(json(
+        &serde_json::json!({ "info_hash": to_hex(&info_hash), "blacklisted": true }),
+        if added {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        },
+    ))
+}
+
+async fn remove_from_blacklist(InfoHash(info_hash): InfoHash) -> Result<Response> {
+    Ok(if unblacklist_entry(&info_hash).await? {
+        json(
+            &serde_json::json!({ "info_hash": to_hex(&info_hash), "blacklisted": false }),
+            StatusCode::OK,
+        )
+    } else {
+        failure(StatusCode::NOT_FOUND, "torrent is not blacklisted")
+    })
+}
+
+/// Sums a counter over the label at `index`, e.g. the requests of each route over all statuses.
+fn sum_by_label(series: Vec<(Vec<String>, u64)>, index: usize) -> BTreeMap<String, u64> {
+    let mut sums = BTreeMap::new();
+    for (labels, count) in series {
+        *sums.entry(labels[index].clone()).or_default() += count;
+    }
+    sums
+}
+
+async fn show_stats() -> Result<Response> {
+    let metrics = metrics();
+    // Labels are ordered by name, i.e. `route` before `status`.
+    let requests = counter_values(&metrics.requests);
+    let server_errors: u64 = requests
+        .iter()
+        .filter(|(labels, _)| labels[1].starts_with('5'))
+        .map(|(_, count)| count)
+        .sum();
+    Ok(json(
+        &serde_json::json!({
+            "uptime": liveness().uptime,
+            "cache": {
+                "entries": metrics.cache_entries.get(),
+                "bytes": metrics.cache_bytes.get(),
+            },
+            "lookups": sum_by_label(counter_values(&metrics.cache_lookups), 0),
+            "requests": sum_by_label(requests, 0),
+            "server_errors": server_errors,
+            "blacklisted": list_blacklist().len(),
+        }),
+        StatusCode::OK,
+    ))
+}
+
+async fn show_hottest(query: HottestQuery) -> Result<Response> {
+    let mut result = Vec::new();
+    for (info_hash, requests) in hottest_entries(query.limit.unwrap_or(DEFAULT_HOTTEST)) {
+        let cache = read_entry(&info_hash).await?;
+        result.push(HotEntry {
+            info_hash: to_hex(&info_hash),
+            requests,
+            peers: cache.as_ref().map(|x| x.peers_addr.len()),
+            expires: cache.as_ref().map(|x| unix(x.expiration())),
+        });
+    }
+    Ok(json(&result, StatusCode::OK))
+}
+
+async fn show_trackers() -> Result<Response> {
+    // Labels are ordered by name, i.e. `host` before `outcome`.
+    let mut hosts: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for (labels, count) in counter_values(&metrics().origin_announces) {
+        hosts
+            .entry(labels[0].clone())
+            .or_default()
+            .insert(labels[1].clone(), count);
+    }
+    let circuits: Vec<_> = circuit_states()
+        .into_iter()
+        .map(|(tracker, state)| match state {
+            CircuitState::Closed { failures } => CircuitSummary {
+                tracker,
+                state: "closed",
+                failures: Some(failures),
+                retry_in: None,
+            },
+            CircuitState::Open { remaining } => CircuitSummary {
+                tracker,
+                state: "open",
+                failures: None,
+                retry_in: Some(remaining.as_secs()),
+            },
+            CircuitState::HalfOpen => CircuitSummary {
+                tracker,
+                state: "half_open",
+                failures: None,
+                retry_in: None,
+            },
+        })
+        .collect();
+    Ok(json(
+        &serde_json::json!({ "hosts": hosts, "circuits": circuits }),
+        StatusCode::OK,
+    ))
+}
+
+async fn show_errors() -> Result<Response> {
+    let errors: Vec<_> = recent_errors()
+        .into_iter()
+        .rev()
+        .map(|x| ErrorSummary {
+            time: unix(x.time),
+            level: x.level.to_string(),
+            message: x.message,
+        })
+        .collect();
+    Ok(json(&errors, StatusCode::OK))
+}
+
+/// The dashboard, which only exists along with the API.
+async fn show_dashboard() -> Result<impl Reply, Rejection> {
+    if config().server.admin_token.is_none() {
+        return Err(warp::reject::not_found());
+    }
+    Ok(warp::reply::html(DASHBOARD))
+}
+
+pub(crate) fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("torrents"))
+        .and(warp::query::<ListQuery>())
+        .then(list_torrents);
+    let show = warp::get()
+        .and(warp::path!("torrents" / InfoHash))
+        .then(show_torrent);
+    let refresh = warp::post()
+        .and(warp::path!("torrents" / InfoHash / "refresh"))
+        .and(warp::query::<RefreshQuery>())
+        .then(refresh_torrent);
+    let purge = warp::delete()
+        .and(warp::path!("torrents" / InfoHash))
+        .then(purge_torrent);
+    let purge_all = warp::delete().and(warp::path!("torrents")).then(purge_all);
+    let blacklist = warp::get()
+        .and(warp::path!("blacklist"))
+        .then(show_blacklist);
+    let blacklist_add = warp::put()
+        .and(warp::path!("blacklist" / InfoHash))
+        .then(add_to_blacklist);
+    let blacklist_remove = warp::delete()
+        .and(warp::path!("blacklist" / InfoHash))
+        .then(remove_from_blacklist);
+    let stats = warp::get().and(warp::path!("stats")).then(show_stats);
+    let hottest = warp::get()
+        .and(warp::path!("hottest"))
+        .and(warp::query::<HottestQuery>())
+        .then(show_hottest);
+    let trackers = warp::get().and(warp::path!("trackers")).then(show_trackers);
+    let errors = warp::get().and(warp::path!("errors")).then(show_errors);
+
+    let dashboard = warp::get()
+        .and(warp::path!("admin"))
+        .and_then(show_dashboard);
+
+    let api = warp::path!("admin" / "api" / ..)
+        .and(authorized())
+        .and(
+            list.or(show)
+                .unify()
+                .or(refresh)
+                .unify()
+                .or(purge)
+                .unify()
+                .or(purge_all)
+                .unify()
+                .or(blacklist)
+                .unify()
+                .or(blacklist_add)
+                .unify()
+                .or(blacklist_remove)
+                .unify()
+                .or(stats)
+                .unify()
+                .or(hottest)
+                .unify()
+                .or(trackers)
+                .unify()
+                .or(errors)
+                .unify(),
+        )
+        .map(respond)
+        .recover(recover);
+
+    dashboard.or(api)
+}
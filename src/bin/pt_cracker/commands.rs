@@ -0,0 +1,235 @@
+//! Offline subcommands, for maintenance and debugging without running the web server.
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, Result};
+use clap::Subcommand;
+use pt_cracker::{
+    cache::{collect_garbage, init_cache_store, list_entries, purge_entry, read_entry},
+    metainfo::{Metainfo, parse_info_hash, to_hex, transform_torrent},
+    tracker,
+};
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Run the web server. This is the default.
+    Serve,
+    /// Point the trackers of a torrent file to this service.
+    Transform {
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Show the metadata and info hash of a torrent file.
+    Inspect { file: PathBuf },
+    /// Manage the peer list cache.
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Announce to an origin tracker and show its response, bypassing the cache.
+    Announce {
+        #[arg(long)]
+        tracker_url: String,
+        /// As 40 hex digits or percent-encoded.
+        #[arg(long)]
+        info_hash: String,
+        /// Size of the torrent in bytes, defaulting to the cached size.
+        #[arg(long)]
+        size: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+pub(crate) enum CacheCommand {
+    /// List cached torrents.
+    List,
+    /// Show the cache of a torrent.
+    Show { info_hash: String },
+    /// Delete the cache of torrents.
+    Purge {
+        #[arg(required_unless_present = "all")]
+        info_hashes: Vec<String>,
+        /// Delete everything.
+        #[arg(long, conflicts_with = "info_hashes")]
+        all: bool,
+    },
+    /// Collect garbage once, as the server does periodically.
+    Gc,
+}
+
+pub(crate) async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("the server is started by main"),
+        Command::Transform { input, output } => transform(&input, &output),
+        Command::Inspect { file } => inspect(&file),
+        Command::Cache(command) => {
+            init_cache_store()?;
+            cache(command).await
+        }
+        Command::Announce {
+            tracker_url,
+            info_hash,
+            size,
+        } => {
+            init_cache_store()?;
+            announce(&tracker_url, &info_hash, size).await
+        }
+    }
+}
+
+fn transform(input: &Path, output: &Path) -> Result<()> {
+    let torrent =
+        std::fs::read(input).with_context(|| format!("failed to read {}", input.display()))?;
+    std::fs::write(output, transform_torrent(&torrent)?)
+        .with_context(|| format!("failed to write {}", output.display()))
+}
+
+fn inspect(file: &Path) -> Result<()> {
+    let torrent =
+        std::fs::read(file).with_context(|| format!("failed to read {}", file.display()))?;
+    let metainfo = Metainfo::parse(&torrent)?;
+
+    if let Some(name) = &metainfo.name {
+        println!("name: {name}");
+    }
+    if let Some(size) = metainfo.size {
+        println!("size: {} bytes in {} file(s)", size, metainfo.file_count);
+    }
+    if let Some(piece_length) = metainfo.piece_length {
+        println!("piece length: {piece_length} bytes");
+    }
+    if let Some(piece_count) = metainfo.piece_count {
+        println!("pieces: {piece_count}");
+    }
+    println!("private: {}", metainfo.private);
+    println!("info hash: {}", to_hex(&metainfo.info_hash));
+    if let Some(info_hash_v2) = &metainfo.info_hash_v2 {
+        println!("info hash v2: {}", to_hex(info_hash_v2));
+    }
+    for (i, tier) in metainfo.tiers.iter().enumerate() {
+        println!("tracker tier {}: {}", i + 1, tier.join(" "));
+    }
+    Ok(())
+}
+
+/// Formats `time` relative to now, e.g. `in 60s` or `5s ago`.
+fn relative(time: SystemTime) -> String {
+    match time.duration_since(SystemTime::now()) {
+        Ok(x) => format!("in {}s", x.as_secs()),
+        Err(e) => format!("{}s ago", e.duration().as_secs()),
+    }
+}
+
+async fn cache(command: CacheCommand) -> Result<()> {
+    match command {
+        CacheCommand::List => {
+            for info_hash in list_entries().await? {
+                let Some(cache) = read_entry(&info_hash).await? else {
+                    continue;
+                };
+                println!(
+                    "{}  {} peer(s)  expires {}",
+                    to_hex(&info_hash),
+                    cache.peers_addr.len(),
+                    relative(cache.expiration())
+                );
+            }
+        }
+        CacheCommand::Show { info_hash } => {
+            let info_hash = parse_info_hash(&info_hash)?;
+            let cache = read_entry(&info_hash)
+                .await?
+                .ok_or(anyhow::anyhow!("torrent is not cached"))?;
+            println!("info hash: {}", to_hex(&info_hash));
+            println!("size: {} bytes", cache.size);
+            println!("downloaded: {}", cache.downloaded);
+            println!("clients: {}", cache.clients.len());
+            for (tracker, &expiration) in &cache.trackers {
+                let announced = cache.announced.get(tracker).copied();
+                println!(
+                    "tracker: {}  expires {}  announced {}",
+                    tracker,
+                    relative(expiration),
+                    announced.map_or("never".to_string(), relative)
+                );
+            }
+            for peer in &cache.peers_time {
+                let sources = cache
+                    .peer_sources
+                    .get(&peer.addr)
+                    .map(|x| x.trackers.iter().cloned().collect::<Vec<_>>().join(" "))
+                    .unwrap_or_default();
+                println!(
+                    "peer: {}  expires {}  {}",
+                    peer.addr,
+                    relative(peer.expire),
+                    sources
+                );
+            }
+        }
+        CacheCommand::Purge { info_hashes, all } => {
+            let info_hashes = if all {
+                list_entries().await?
+            } else {
+                info_hashes
+                    .iter()
+                    .map(|x| parse_info_hash(x))
+                    .collect::<Result<_>>()?
+            };
+            for info_hash in info_hashes {
+                if !purge_entry(&info_hash).await? {
+                    eprintln!("Warning: {} is not cached", to_hex(&info_hash));
+                }
+            }
+        }
+        CacheCommand::Gc => collect_garbage().await?,
+    }
+    Ok(())
+}
+
+async fn announce(tracker_url: &str, info_hash: &str, size: Option<u64>) -> Result<()> {
+    let info_hash = parse_info_hash(info_hash)?;
+    let size = match size {
+        Some(size) => size,
+        None => read_entry(&info_hash)
+            .await?
+            .map(|x| x.size)
+            .ok_or(anyhow::anyhow!("torrent size is unknown, pass --size"))?,
+    };
+    let response = tracker::announce(tracker_url, &info_hash, size).await?;
+
+    if let Some(failure_reason) = &response.failure_reason {
+        println!("failure reason: {failure_reason}");
+    }
+    if let Some(warning_message) = &response.warning_message {
+        println!("warning message: {warning_message}");
+    }
+    if let Some(interval) = response.interval {
+        println!("interval: {interval}s");
+    }
+    if let Some(min_interval) = response.min_interval {
+        println!("min interval: {min_interval}s");
+    }
+    if let Some(seeders) = response.seeders {
+        println!("seeders: {seeders}");
+    }
+    if let Some(leechers) = response.leechers {
+        println!("leechers: {leechers}");
+    }
+    let peers = response
+        .peers
+        .iter()
+        .flat_map(|x| tracker::deserialize_peers_binary(x))
+        .chain(
+            response
+                .peers6
+                .iter()
+                .flat_map(|x| tracker::deserialize_peers6_binary(x)),
+        );
+    for peer in peers {
+        println!("peer: {peer}");
+    }
+    Ok(())
+}
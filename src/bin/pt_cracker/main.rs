@@ -0,0 +1,35 @@
+//! The `pt_cracker` command, which runs the web server by default.
+
+mod admin;
+mod commands;
+mod server;
+
+use anyhow::Result;
+use clap::Parser;
+use pt_cracker::{
+    config::{Config, Overrides, init_config},
+    logging::init_logging,
+};
+
+use crate::commands::Command;
+
+/// A peer list cache service for private trackers.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    overrides: Overrides,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_config(Config::load(cli.overrides)?)?;
+    init_logging()?;
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => server::serve().await,
+        command => commands::run(command).await,
+    }
+}
@@ -0,0 +1,423 @@
+//! The web server, answering announces from the cache and rewriting uploaded torrents.
+
+use std::{
+    convert::Infallible,
+    pin::pin,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use bytes::BufMut;
+use futures::StreamExt as _;
+use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+use pt_cracker::{
+    cache::{
+        Blacklisted, TorrentCache, fetch_cache, flush_cache_store, init_cache_store,
+        is_circuit_open, is_throttled, read_entry, run_garbage_collector, update_cache,
+    },
+    config::config,
+    health::{Status, liveness, mark_started, readiness},
+    metainfo::transform_torrent,
+    metrics::metrics,
+    tracker::{
+        AnnounceResponse, ScrapeResponse, get_raw_query_param, get_raw_query_params,
+        negotiate_interval,
+    },
+};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{sync::watch, time::Instant};
+use tracing::{error, info, info_span, warn};
+use warp::{Filter, http::StatusCode};
+
+use crate::admin;
+
+/// Torrents answered by a single scrape at most, bounding the cache reads it causes.
+const MAX_SCRAPE_TORRENTS: usize = 100;
+
+macro_rules! unwrap_option_or_error {
+    ($value: expr) => {{
+        let value = $value;
+        if value.is_none() {
+            return Ok(warp::http::Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(warp::hyper::body::Bytes::from(format!(
+                    "Missing parameter: {}",
+                    stringify!($value)
+                )))
+                .unwrap());
+        }
+        unsafe { value.unwrap_unchecked() }
+    }};
+}
+
+macro_rules! unwrap_result_or_error {
+    ($value: expr) => {{
+        let value = $value;
+        if let Err(error) = value {
+            error!("request failed: {error:#}");
+            return Ok(warp::http::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(warp::hyper::body::Bytes::from(format!(
+                    "Server error: {}",
+                    error
+                )))
+                .unwrap());
+        }
+        unsafe { value.unwrap_unchecked() }
+    }};
+}
+
+/// Counts a served request and how long it took. Paths other than our routes are counted as
+/// `other`, so that scanners cannot make up new series.
+fn record_request(info: warp::log::Info<'_>) {
+    let route = match info.path() {
+        "/" => "index",
+        "/announce" => "announce",
+        "/scrape" => "scrape",
+        "/transform" => "transform",
+        "/metrics" => "metrics",
+        "/healthz" => "healthz",
+        "/readyz" => "readyz",
+        "/admin" => "admin",
+        path if path.starts_with("/admin/api/") => "admin",
+        _ => "other",
+    };
+    let metrics = metrics();
+    metrics
+        .requests
+        .with_label_values(&[route, info.status().as_str()])
+        .inc();
+    metrics
+        .request_duration
+        .with_label_values(&[route])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// A span per request, so that everything logged while serving it carries a random ID.
+fn request_span(info: warp::trace::Info<'_>) -> tracing::Span {
+    info_span!(
+        "request",
+        id = %format!("{:016x}", rand::random::<u64>()),
+        method = %info.method(),
+        path = info.path(),
+    )
+}
+
+/// Resolves on SIGTERM, as sent by `docker stop` and systemd, or on Ctrl-C. A signal which cannot
+/// be listened to is logged and never arrives, rather than stopping the service right away.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for Ctrl-C: {e}");
+            std::future::pending().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("failed to listen for SIGTERM: {e}");
+                std::future::pending().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = interrupt => (),
+        () = terminate => (),
+    }
+}
+
+/// How old the cache may be before a `started` event forces a re-announce to origin.
+fn get_started_freshness() -> Duration {
+    Duration::from_secs(config().server.started_freshness)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AnnounceQuery {
+    tracker_url: String,
+    ttl: u64,
+    downloaded: u64,
+    left: u64,
+    event: Option<String>,
+    /// JSON-encoded BEP 12 tiers of origin trackers, replacing `tracker_url` if present.
+    tiers: Option<String>,
+}
+
+pub(crate) async fn serve() -> Result<()> {
+    let index_path = &config().server.index_path;
+    if !index_path.is_file() {
+        return Err(anyhow::anyhow!(
+            "invalid configuration: index_path {} is not a file",
+            index_path.display()
+        ));
+    }
+    mark_started();
+    init_cache_store()?;
+
+    // Everything running in the background stops once this turns true.
+    let (stop, stopping) = watch::channel(false);
+    let stopped = move || {
+        let mut stopping = stopping.clone();
+        async move {
+            let _ = stopping.wait_for(|&x| x).await;
+        }
+    };
+    let mut gc = tokio::spawn(run_garbage_collector(stopped()));
+
+    let announce = warp::get()
+        .and(warp::path("announce"))
+        .and(warp::query::raw())
+        .and(warp::query::<AnnounceQuery>())
+        .and_then(move |p: String, q: AnnounceQuery| async move {
+            let info_hash = unwrap_option_or_error!(get_raw_query_param(&p, "info_hash"));
+            let peer_id = get_raw_query_param(&p, "peer_id");
+            if peer_id.as_ref().is_some_and(|x| x.len() != 20) {
+                return Ok(warp::http::Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(warp::hyper::body::Bytes::from(
+                        "Invalid parameter: peer_id must be 20 bytes",
+                    ))
+                    .unwrap());
+            }
+            let peer_id = peer_id.map(|x| percent_encode(&x, NON_ALPHANUMERIC).to_string());
+            let ttl = Duration::from_secs(q.ttl.min(config().server.max_ttl));
+
+            // `stopped` only unregisters the client. `completed` is counted once the cache
+            // exists, so that the first announce of a torrent is counted too, and only once per
+            // client until its registration expires, even if it stopped in between. A `started`
+            // client re-announces to origin if our last announce is older than the freshness
+            // bound, so that a new session never sees a stale peer list.
+            let mut max_age = None;
+            match q.event.as_deref() {
+                Some("stopped") => {
+                    if let Some(peer_id) = peer_id {
+                        unwrap_result_or_error!(
+                            update_cache(&info_hash, |cache| {
+                                cache.clients.remove(&peer_id);
+                            })
+                            .await
+                        );
+                    }
+                    let response: AnnounceResponse = TorrentCache::default().into();
+                    let bytes = unwrap_result_or_error!(bt_bencode::to_vec(&response));
+                    return Result::<_, Infallible>::Ok(
+                        warp::http::Response::builder()
+                            .status(StatusCode::OK)
+                            .body(warp::hyper::body::Bytes::from(bytes))
+                            .unwrap(),
+                    );
+                }
+                Some("started") => {
+                    max_age = Some(get_started_freshness());
+                }
+                _ => (),
+            }
+
+            // Torrents transformed in multi-tracker mode carry all of their trackers.
+            let tiers = match q.tiers.as_deref().map(serde_json::from_str) {
+                Some(tiers) => unwrap_result_or_error!(tiers),
+                None => vec![vec![q.tracker_url]],
+            };
+            let tracker_urls: Vec<String> = tiers.iter().flatten().cloned().collect();
+            let cache = fetch_cache(
+                tiers,
+                &info_hash,
+                if q.downloaded == 0 {
+                    Some(q.left)
+                } else {
+                    None
+                },
+                ttl,
+                max_age,
+            )
+            .await;
+            // Let the client know the origin is down or busy, or the torrent is not served,
+            // instead of failing with a server error.
+            if let Err(e) = &cache
+                && (is_circuit_open(e) || is_throttled(e) || e.is::<Blacklisted>())
+            {
+                let response = AnnounceResponse::failure(e.to_string());
+                let bytes = unwrap_result_or_error!(bt_bencode::to_vec(&response));
+                return Result::<_, Infallible>::Ok(
+                    warp::http::Response::builder()
+                        .status(StatusCode::OK)
+                        .body(warp::hyper::body::Bytes::from(bytes))
+                        .unwrap(),
+                );
+            }
+            let mut cache = unwrap_result_or_error!(cache);
+            let completed = peer_id.clone().filter(|peer_id| {
+                q.event.as_deref() == Some("completed") && !cache.completed.contains_key(peer_id)
+            });
+            let new_client = peer_id.filter(|peer_id| {
+                q.event.as_deref() == Some("started") || !cache.clients.contains_key(peer_id)
+            });
+            if (completed.is_some() || new_client.is_some())
+                && let Some(updated_cache) = unwrap_result_or_error!(
+                    update_cache(&info_hash, |cache| {
+                        let expire = SystemTime::now() + ttl;
+                        if let Some(peer_id) = completed
+                            && cache.completed.insert(peer_id, expire).is_none()
+                        {
+                            cache.downloaded += 1;
+                        }
+                        if let Some(peer_id) = new_client {
+                            cache.clients.insert(peer_id, expire);
+                        }
+                    })
+                    .await
+                )
+            {
+                cache = updated_cache;
+            }
+            let interval = negotiate_interval(
+                cache
+                    .nearest_expiry(tracker_urls.iter().map(String::as_str))
+                    .min(ttl),
+            );
+            let mut response: AnnounceResponse = cache.into();
+            response.interval = Some(interval);
+
+            let bytes = unwrap_result_or_error!(bt_bencode::to_vec(&response));
+            let bytes = warp::hyper::body::Bytes::from(bytes);
+
+            Result::<_, Infallible>::Ok(
+                warp::http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(bytes)
+                    .unwrap(),
+            )
+        });
+
+    // Scrapes are answered from the cache alone, without contacting origin trackers. Clients
+    // derive the scrape URL from the announce URL, so they carry our parameters along.
+    let scrape = warp::get()
+        .and(warp::path("scrape"))
+        .and(warp::query::raw())
+        .and_then(|p: String| async move {
+            let now = SystemTime::now();
+            let mut response = ScrapeResponse::default();
+            for info_hash in get_raw_query_params(&p, "info_hash").take(MAX_SCRAPE_TORRENTS) {
+                if let Some(mut cache) = unwrap_result_or_error!(read_entry(&info_hash).await) {
+                    cache.prune(now);
+                    response
+                        .files
+                        .insert(info_hash.into_vec().into(), (&cache).into());
+                }
+            }
+            let bytes = unwrap_result_or_error!(bt_bencode::to_vec(&response));
+
+            Result::<_, Infallible>::Ok(
+                warp::http::Response::builder()
+                    .status(StatusCode::OK)
+                    .body(warp::hyper::body::Bytes::from(bytes))
+                    .unwrap(),
+            )
+        });
+
+    let transform = warp::post()
+        .and(warp::path("transform"))
+        .and(warp::multipart::form())
+        .and_then(|mut form: warp::multipart::FormData| async move {
+            while let Some(part) = form.next().await {
+                let part = unwrap_result_or_error!(part);
+                if part.name() != "file" {
+                    continue;
+                }
+                let mut stream = part.stream();
+                let mut buf = Vec::new();
+                while let Some(x) = stream.next().await {
+                    let x = unwrap_result_or_error!(x);
+                    BufMut::put(&mut buf, x);
+                }
+                let modified_torrent_bytes = unwrap_result_or_error!(transform_torrent(&buf));
+
+                return Result::<_, Infallible>::Ok(
+                    warp::http::Response::builder()
+                        .status(StatusCode::OK)
+                        .body(warp::hyper::body::Bytes::copy_from_slice(
+                            &modified_torrent_bytes,
+                        ))
+                        .unwrap(),
+                );
+            }
+
+            unwrap_result_or_error!(Err::<(), &str>("no files are uploaded"));
+            unreachable!();
+        });
+
+    let index = warp::get()
+        .and(warp::path::end())
+        .and(warp::fs::file(config().server.index_path.clone()));
+
+    let metrics = warp::get().and(warp::path("metrics")).map(|| {
+        let text = unwrap_result_or_error!(metrics().render());
+        warp::http::Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(warp::hyper::body::Bytes::from(text))
+    });
+
+    let healthz = warp::get()
+        .and(warp::path("healthz"))
+        .map(|| warp::reply::json(&liveness()));
+
+    let readyz = warp::get().and(warp::path("readyz")).then(|| async {
+        let readiness = readiness().await;
+        let status = match readiness.status {
+            Status::Ok => StatusCode::OK,
+            Status::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        warp::reply::with_status(warp::reply::json(&readiness), status)
+    });
+
+    let routes = admin::routes()
+        .or(index)
+        .or(transform)
+        .or(announce)
+        .or(scrape)
+        .or(metrics)
+        .or(healthz)
+        .or(readyz)
+        .with(warp::log::custom(record_request))
+        .with(warp::trace(request_span));
+    let bind = config().server.bind;
+    let listener = tokio::net::TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("failed to listen on {bind}"))?;
+    let server = warp::serve(routes)
+        .incoming(listener)
+        .graceful(stopped())
+        .run();
+    info!("listening on {bind}");
+
+    // On shutdown, new connections are refused right away, while requests in flight, and the
+    // cache writes they make, get until the deadline to finish.
+    let mut server = pin!(server);
+    tokio::select! {
+        () = &mut server => (),
+        () = shutdown_signal() => (),
+    }
+    let timeout = Duration::from_secs(config().server.shutdown_timeout);
+    info!(
+        "shutting down, waiting up to {}s for in-flight requests",
+        timeout.as_secs()
+    );
+    let deadline = Instant::now() + timeout;
+    stop.send_replace(true);
+    if tokio::time::timeout_at(deadline, server).await.is_err() {
+        warn!("in-flight requests did not finish in time");
+    }
+    if tokio::time::timeout_at(deadline, &mut gc).await.is_err() {
+        warn!("garbage collection did not finish in time");
+        gc.abort();
+    }
+    flush_cache_store().await?;
+    info!("stopped");
+
+    Ok(())
+}
@@ -5,7 +5,7 @@
 //! they fail to parse real-world torrent files as they often contains raw bytes. This simple
 //! parser correctly handles general cases.
 //!
-//! This module is only meant to be used within this project instead of for general use, because it
+//! This module is meant for tools around this project rather than for general use, because it
 //! does not provide a formal interface, and does not implement standard-conforming error
 //! handling. For example, the `into`-`try_from` roundtrip results in a different bencode object
 //! since `TryFrom<&[u8]>` adds an outer [`BencodeObject::List`] to the object.
@@ -25,7 +25,7 @@ fn to_digit(x: char) -> Result<usize> {
 }
 
 #[derive(Debug)]
-pub(crate) enum BencodeObject {
+pub enum BencodeObject {
     None,
     Integer(String),
     /// If size is zero, then vec is None.
@@ -48,38 +48,38 @@ impl TryFrom<&[u8]> for BencodeObject {
     }
 }
 
-impl Into<Vec<u8>> for BencodeObject {
+impl From<BencodeObject> for Vec<u8> {
     /// Serializes bencode objects.
-    fn into(self) -> Vec<u8> {
+    fn from(value: BencodeObject) -> Self {
         let mut result = Vec::new();
-        match self {
+        match value {
             BencodeObject::None => (),
             BencodeObject::Integer(value) => {
-                result.push('i' as u8);
+                result.push(b'i');
                 result.extend(value.as_bytes());
-                result.push('e' as u8);
+                result.push(b'e');
             }
             BencodeObject::Bytes(size, value) => {
                 result.extend(size.to_string().as_bytes());
-                result.push(':' as u8);
+                result.push(b':');
                 if let Some(value) = value {
                     result.extend(value);
                 }
             }
             BencodeObject::List(list) => {
-                result.push('l' as u8);
+                result.push(b'l');
                 for item in list {
                     result.extend(Into::<Vec<u8>>::into(item));
                 }
-                result.push('e' as u8);
+                result.push(b'e');
             }
             BencodeObject::Dictionary(dict) => {
-                result.push('d' as u8);
+                result.push(b'd');
                 for (k, v) in dict {
                     result.extend(Into::<Vec<u8>>::into(k));
                     result.extend(Into::<Vec<u8>>::into(v));
                 }
-                result.push('e' as u8);
+                result.push(b'e');
             }
         }
         result
@@ -135,7 +135,7 @@ impl BencodeParser {
                     if x == 'e' {
                         push = true;
                     } else {
-                        value.extend_one(x);
+                        value.push(x);
                     }
                 }
                 BencodeObject::Bytes(size, value) => {
@@ -2,281 +2,886 @@
 
 use std::{
     collections::{BTreeSet, HashMap},
-    mem::take,
     net::SocketAddr,
-    path::{Path, PathBuf},
-    sync::{Arc, LazyLock},
-    time::{Duration, SystemTime},
+    path::PathBuf,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::Result;
-use percent_encoding::{NON_ALPHANUMERIC, percent_encode, utf8_percent_encode};
+use anyhow::{Context as _, Result};
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, percent_encode, utf8_percent_encode};
+use rand::{Rng as _, seq::SliceRandom as _};
 use serde_derive::{Deserialize, Serialize};
-use tokio::{
-    fs::create_dir_all,
-    io::{AsyncReadExt as _, AsyncWriteExt as _},
-    sync::{Mutex, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, Semaphore},
-    time::timeout,
-};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
 use url::Url;
 
-use crate::tracker;
+use self::{
+    lock::KeyedLock,
+    singleflight::{SharedError, Singleflight},
+    store::{CacheBackend, CacheStore as _},
+};
+use crate::{
+    config::{CacheBackendKind, config, user_cache_dir},
+    metainfo::to_hex,
+    metrics::metrics,
+    tracker::{
+        self, AnnounceResponse, CircuitBreakers, CircuitOpen, CircuitState, OriginLimits, Throttled,
+    },
+};
+
+mod blacklist;
+mod codec;
+mod gc;
+mod hot;
+mod lock;
+mod singleflight;
+pub mod store;
+
+pub use self::{
+    blacklist::{Blacklisted, blacklist_entry, is_blacklisted, list_blacklist, unblacklist_entry},
+    gc::{collect_garbage, run_garbage_collector},
+    hot::HotTier,
+};
 
-/// In-memory solution to concurrent race. The key of the hash map is percent-encoded `info_hash`,
-/// and the value consists of a lock and a reference count. When the rc is zeroed, the entry is
-/// removed from the map to save memory. Uses read-write locks for better performance.
-static CACHE_LOCKS: LazyLock<Mutex<HashMap<String, (Arc<RwLock<()>>, usize)>>> =
-    LazyLock::new(Default::default);
+/// In-memory solution to concurrent race, keyed by percent-encoded `info_hash`. Uses read-write
+/// locks for better performance.
+static CACHE_LOCKS: LazyLock<KeyedLock> = LazyLock::new(Default::default);
+/// When each cache entry was last requested since startup and how often, keyed by percent-encoded
+/// `info_hash`. Used for LRU eviction by the garbage collector, and to find the hottest torrents.
+static ACCESSES: LazyLock<Mutex<HashMap<String, Access>>> = LazyLock::new(Default::default);
 /// Since the public instance uses a rotated IP pool which poses a limit on concurrently opened
-/// connections, we use a semaphore to control connections to origin trackers.
-static TRACKER_CONNECTIONS: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(10));
+/// connections, and origin trackers may ban clients announcing too often, we limit connections to
+/// them both globally and per host.
+static ORIGIN_LIMITS: LazyLock<OriginLimits> = LazyLock::new(|| {
+    let origin = &config().origin;
+    OriginLimits::new(
+        origin.connections,
+        origin.host_connections,
+        origin.host_rate,
+        origin.host_burst,
+    )
+});
+/// In-flight refreshes from origin, keyed by percent-encoded `info_hash` and tracker.
+static REFRESHES: LazyLock<Singleflight<(TorrentCache, Lookup)>> = LazyLock::new(Default::default);
+/// Circuit breakers of origin trackers, keyed by [`tracker::tracker_key`].
+static BREAKERS: LazyLock<CircuitBreakers> = LazyLock::new(|| {
+    let origin = &config().origin;
+    CircuitBreakers::new(
+        origin.breaker_threshold,
+        Duration::from_secs(origin.breaker_cooldown),
+    )
+});
+
+#[derive(Clone, Copy)]
+struct Access {
+    last: SystemTime,
+    count: u64,
+}
+
+/// How a cache lookup was served, as counted by [`crate::metrics`].
+#[derive(Clone, Copy)]
+enum Lookup {
+    Hit,
+    Miss,
+    Stale,
+}
+
+impl Lookup {
+    fn record(self) {
+        let result = match self {
+            Lookup::Hit => "hit",
+            Lookup::Miss => "miss",
+            Lookup::Stale => "stale",
+        };
+        metrics().cache_lookups.with_label_values(&[result]).inc();
+    }
+}
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone, Copy)]
-pub(crate) struct Peer {
-    pub(crate) expire: SystemTime,
-    pub(crate) addr: SocketAddr,
+pub struct Peer {
+    pub expire: SystemTime,
+    pub addr: SocketAddr,
+}
+
+/// Where a cached peer came from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeerProvenance {
+    /// Origin trackers which reported this peer, keyed the same way as [`TorrentCache::trackers`].
+    pub trackers: BTreeSet<String>,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
 }
 
 /// This struct has two copies of peer list: [`TorrentCache::peers_time`] is for removing overdue
 /// peers, and [`TorrentCache::peers_addr`] is for detecting duplicates in the cache.
 #[derive(Serialize, Deserialize, Default, Clone)]
-pub(crate) struct TorrentCache {
-    pub(crate) size: u64,
-    pub(crate) trackers: HashMap<String, SystemTime>,
-    pub(crate) peers_time: BTreeSet<Peer>,
-    pub(crate) peers_addr: HashMap<SocketAddr, SystemTime>,
+pub struct TorrentCache {
+    pub size: u64,
+    pub trackers: HashMap<String, SystemTime>,
+    pub peers_time: BTreeSet<Peer>,
+    pub peers_addr: HashMap<SocketAddr, SystemTime>,
+    /// When each origin tracker was last announced to. Used to decide whether a `started` event
+    /// should force a refresh.
+    #[serde(default)]
+    pub announced: HashMap<String, SystemTime>,
+    /// Local clients announcing through this service, keyed by percent-encoded `peer_id`.
+    #[serde(default)]
+    pub clients: HashMap<String, SystemTime>,
+    /// Number of local clients which completed the download.
+    #[serde(default)]
+    pub downloaded: u64,
+    /// Clients already counted in [`TorrentCache::downloaded`], keyed like
+    /// [`TorrentCache::clients`], until their registration would expire.
+    #[serde(default)]
+    pub completed: HashMap<String, SystemTime>,
+    /// Provenance of each peer in [`TorrentCache::peers_addr`]. Peers cached before provenance was
+    /// recorded have no entry.
+    #[serde(default)]
+    pub peer_sources: HashMap<SocketAddr, PeerProvenance>,
 }
 
-fn get_cache_root_dir() -> PathBuf {
-    let cache_root_dir = std::env::var("CACHE_ROOT")
-        .map(|x| Path::new(&x).to_owned())
-        .unwrap_or(
-            std::env::var("XDG_CACHE_HOME")
-                .map(|x| Path::new(&x).to_owned())
-                .unwrap_or(Path::new(&std::env::var("HOME").unwrap()).join(".cache")),
-        );
-    cache_root_dir.join("pt_cracker")
-}
+impl TorrentCache {
+    /// Removes peers and clients that expired before `now`. Returns whether anything was removed.
+    pub fn prune(&mut self, now: SystemTime) -> bool {
+        let clients_len = self.clients.len();
+        let completed_len = self.completed.len();
+        self.clients.retain(|_, &mut expire| expire >= now);
+        self.completed.retain(|_, &mut expire| expire >= now);
+        let mut pruned = self.clients.len() != clients_len || self.completed.len() != completed_len;
+        self.peers_time
+            .extract_if(.., |&peer| peer.expire < now)
+            .for_each(|peer| {
+                self.peers_addr.remove(&peer.addr);
+                self.peer_sources.remove(&peer.addr);
+                pruned = true;
+            });
+        pruned
+    }
 
-async fn read_cache(info_hash: &str) -> Result<Option<TorrentCache>> {
-    let cache_root_dir = get_cache_root_dir();
-    let cache_path = cache_root_dir.join(info_hash);
+    fn migrate_tracker_keys(&mut self, tiers: &[Vec<TrackerRef>]) {
+        for tracker in tiers.iter().flatten() {
+            self.migrate_tracker_key(&tracker.legacy_key, &tracker.key);
+        }
+    }
 
-    create_dir_all(cache_root_dir).await?;
-    if !tokio::fs::try_exists(&cache_path).await? {
-        return Ok(None);
+    /// Whether any tracker of a tier is fresh. Like clients, a refresh announces to a single
+    /// tracker of each tier, so the others need not be.
+    fn is_fresh_for_tier(&self, tier: &[TrackerRef], max_age: Option<Duration>) -> bool {
+        tier.iter()
+            .any(|tracker| self.is_fresh(&tracker.key, max_age))
     }
 
-    let mut buf = String::new();
-    tokio::fs::File::open(&cache_path)
-        .await?
-        .read_to_string(&mut buf)
-        .await?;
-    Ok(Some(serde_json::from_str(&buf)?))
-}
+    /// Whether every tier is fresh. The same torrent may be announced with only some of these
+    /// trackers, e.g. by a client of another user or of a torrent not in multi-tracker mode, which
+    /// refreshes only their tiers.
+    fn is_fresh_for_tiers(&self, tiers: &[Vec<TrackerRef>], max_age: Option<Duration>) -> bool {
+        tiers
+            .iter()
+            .all(|tier| self.is_fresh_for_tier(tier, max_age))
+    }
 
-async fn write_cache(info_hash: &str, value: &TorrentCache) -> Result<()> {
-    let cache_root_dir = get_cache_root_dir();
-    let cache_path = cache_root_dir.join(info_hash);
+    /// Records a response of the origin tracker with the given key.
+    fn merge_announce(
+        &mut self,
+        tracker_key: &str,
+        tracker_response: AnnounceResponse,
+        ttl: Duration,
+    ) {
+        let now = SystemTime::now();
+        // Respect the minimum announce interval from origin by keeping the cache valid for at
+        // least that long, within our own bound.
+        let ttl = if let Some(min_interval) = tracker_response.min_interval {
+            Duration::from_secs(min_interval)
+                .min(Duration::from_secs(config().server.max_ttl))
+                .max(ttl)
+        } else {
+            ttl
+        };
+        self.trackers.insert(tracker_key.to_string(), now + ttl);
+        self.announced.insert(tracker_key.to_string(), now);
 
-    create_dir_all(cache_root_dir).await?;
+        if tracker_response.reports_torrent_gone() {
+            self.forget_tracker(tracker_key);
+        }
+        let mut new_peers = Vec::new();
+        if let Some(peers) = tracker_response.peers {
+            new_peers.extend(tracker::deserialize_peers_binary(&peers));
+        }
+        if let Some(peers6) = tracker_response.peers6 {
+            new_peers.extend(tracker::deserialize_peers6_binary(&peers6));
+        }
+        for addr in new_peers {
+            self.insert_peer(addr, now + ttl, tracker_key, now);
+        }
+    }
 
-    let buf = serde_json::to_string(value)?;
-    tokio::fs::File::options()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(cache_path)
-        .await?
-        .write_all(buf.as_bytes())
-        .await?;
+    /// Moves everything recorded under an outdated tracker key to `new`, unless `new` is already
+    /// known. Any later write persists the migration.
+    fn migrate_tracker_key(&mut self, old: &str, new: &str) {
+        if self.trackers.contains_key(new) {
+            return;
+        }
+        let Some(expiration) = self.trackers.remove(old) else {
+            return;
+        };
+        self.trackers.insert(new.to_string(), expiration);
+        if let Some(announced) = self.announced.remove(old) {
+            self.announced.insert(new.to_string(), announced);
+        }
+        for provenance in self.peer_sources.values_mut() {
+            if provenance.trackers.remove(old) {
+                provenance.trackers.insert(new.to_string());
+            }
+        }
+    }
 
-    Ok(())
-}
+    /// Adds a peer reported by `tracker` at `now`, or extends its expiration if already known.
+    fn insert_peer(
+        &mut self,
+        addr: SocketAddr,
+        expire: SystemTime,
+        tracker: &str,
+        now: SystemTime,
+    ) {
+        if let Some(entry_expiration) = self.peers_addr.get_mut(&addr) {
+            self.peers_time.remove(&Peer {
+                expire: *entry_expiration,
+                addr,
+            });
+            *entry_expiration = expire;
+        } else {
+            self.peers_addr.insert(addr, expire);
+        }
+        self.peers_time.insert(Peer { expire, addr });
 
-/// A wrapper for the cache lock guard, with manually implemented async-drop method to manipulate
-/// the outer lock and the rc. You should always use [`CacheLockReadGuard::drop`] instead of
-/// [`Drop::drop`].
-struct CacheLockReadGuard {
-    name: String,
-    _inner: OwnedRwLockReadGuard<()>,
-}
+        let provenance = self.peer_sources.entry(addr).or_insert(PeerProvenance {
+            trackers: BTreeSet::new(),
+            first_seen: now,
+            last_seen: now,
+        });
+        provenance.trackers.insert(tracker.to_string());
+        provenance.last_seen = now;
+    }
 
-impl CacheLockReadGuard {
-    async fn new(info_hash: &str) -> Self {
-        let mut cache_locks = CACHE_LOCKS.lock().await;
-        let entry = cache_locks.entry(info_hash.to_string()).or_default();
-        entry.1 += 1;
-        let entry_lock = entry.0.clone();
-        if let Ok(guard) = entry_lock.clone().try_read_owned() {
-            return Self {
-                name: info_hash.to_string(),
-                _inner: guard,
-            };
+    /// Detaches all peers from `tracker`, and drops those no other tracker reported.
+    fn forget_tracker(&mut self, tracker: &str) {
+        let mut orphans = Vec::new();
+        for (&addr, provenance) in &mut self.peer_sources {
+            if provenance.trackers.remove(tracker) && provenance.trackers.is_empty() {
+                orphans.push(addr);
+            }
         }
+        for addr in orphans {
+            self.peer_sources.remove(&addr);
+            if let Some(expire) = self.peers_addr.remove(&addr) {
+                self.peers_time.remove(&Peer { expire, addr });
+            }
+        }
+    }
 
-        drop(cache_locks);
-        let guard = entry_lock.read_owned().await;
+    /// The most recent announce to any origin tracker.
+    fn last_announced(&self) -> SystemTime {
+        self.announced
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
 
-        Self {
-            name: info_hash.to_string(),
-            _inner: guard,
-        }
+    /// The moment the first peer or client expires, after which [`TorrentCache::prune`] changes
+    /// this entry.
+    pub fn next_expiry(&self) -> Option<SystemTime> {
+        self.peers_time
+            .first()
+            .map(|peer| peer.expire)
+            .into_iter()
+            .chain(self.clients.values().copied())
+            .chain(self.completed.values().copied())
+            .min()
+    }
+
+    /// The moment after which nothing in this entry is valid anymore.
+    pub fn expiration(&self) -> SystemTime {
+        self.trackers
+            .values()
+            .chain(self.peers_addr.values())
+            .chain(self.clients.values())
+            .chain(self.completed.values())
+            .copied()
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Time left until the earliest valid entry of the given trackers expires, or zero if none is
+    /// valid. Expired entries are skipped, as those of trackers that failed to respond or nobody
+    /// announces to anymore would otherwise keep the interval at its floor.
+    pub fn nearest_expiry<'a>(&self, tracker_urls: impl IntoIterator<Item = &'a str>) -> Duration {
+        let now = SystemTime::now();
+        tracker_urls
+            .into_iter()
+            .filter_map(|tracker_url| Url::parse(tracker_url).ok())
+            .filter_map(|url| self.trackers.get(&tracker::tracker_key(&url)))
+            .filter_map(|expiration| expiration.duration_since(now).ok())
+            .min()
+            .unwrap_or_default()
     }
 
-    async fn drop(mut self) {
-        let mut cache_locks = CACHE_LOCKS.lock().await;
-        let entry = unsafe { cache_locks.get_mut(&self.name).unwrap_unchecked() };
-        let name = take(&mut self.name);
-        drop(self);
-        entry.1 -= 1;
-        if entry.1 == 0 {
-            cache_locks.remove(&name);
+    /// Whether the cache for the given tracker is neither expired nor older than `max_age`.
+    fn is_fresh(&self, tracker: &str, max_age: Option<Duration>) -> bool {
+        let now = SystemTime::now();
+        let Some(&expiration) = self.trackers.get(tracker) else {
+            return false;
+        };
+        if now >= expiration {
+            return false;
+        }
+        match (max_age, self.announced.get(tracker)) {
+            (None, _) => true,
+            (Some(max_age), Some(&announced)) => {
+                now.duration_since(announced).unwrap_or_default() < max_age
+            }
+            (Some(_), None) => false,
         }
     }
 }
 
-/// A wrapper for the cache lock guard, with manually implemented async-drop method to manipulate
-/// the outer lock and the rc. You should always use [`CacheLockWriteGuard::drop`] instead of
-/// [`Drop::drop`].
-struct CacheLockWriteGuard {
-    name: String,
-    _inner: OwnedRwLockWriteGuard<()>,
+fn get_cache_root_dir() -> PathBuf {
+    // A loaded configuration always has a root. Only the default one falls back here.
+    let cache_root_dir = config()
+        .cache
+        .root
+        .clone()
+        .or_else(user_cache_dir)
+        .unwrap_or_default();
+    cache_root_dir.join("pt_cracker")
+}
+
+/// The storage backend, selected once at startup by [`init_cache_store`].
+static CACHE_STORE: OnceLock<HotTier<CacheBackend>> = OnceLock::new();
+
+pub fn init_cache_store() -> Result<()> {
+    blacklist::load_blacklist()?;
+    CACHE_STORE
+        .set(HotTier::new(
+            CacheBackend::open(config().cache.backend)?,
+            config().cache.hot_entries,
+        ))
+        .map_err(|_| anyhow::anyhow!("cache store is already initialized"))
 }
 
-impl CacheLockWriteGuard {
-    async fn new(info_hash: &str) -> Self {
-        let mut cache_locks = CACHE_LOCKS.lock().await;
-        let entry = cache_locks.entry(info_hash.to_string()).or_default();
-        entry.1 += 1;
-        let entry_lock = entry.0.clone();
-        if let Ok(guard) = entry_lock.clone().try_write_owned() {
-            return Self {
-                name: info_hash.to_string(),
-                _inner: guard,
-            };
-        }
+/// Flushes the cache store before exiting, if it was initialized.
+pub async fn flush_cache_store() -> Result<()> {
+    match CACHE_STORE.get() {
+        Some(store) => store.flush().await.context("failed to flush cache store"),
+        None => Ok(()),
+    }
+}
 
-        drop(cache_locks);
-        let guard = entry_lock.write_owned().await;
+fn cache_store() -> &'static HotTier<CacheBackend> {
+    CACHE_STORE.get().expect("cache store is not initialized")
+}
 
-        Self {
-            name: info_hash.to_string(),
-            _inner: guard,
-        }
+/// Checks that the cache can be written, by creating and deleting a file where the backend keeps
+/// its data. Returns the directory checked, or `None` for the memory backend.
+pub async fn check_cache_dir() -> Result<Option<PathBuf>> {
+    let root = get_cache_root_dir();
+    let dir = match config().cache.backend {
+        CacheBackendKind::Filesystem => root,
+        CacheBackendKind::Sqlite => root.parent().unwrap_or(&root).to_path_buf(),
+        CacheBackendKind::Memory => return Ok(None),
+    };
+    // Hidden, so that the filesystem backend never lists it, and unique, since checks may overlap.
+    let probe = dir.join(format!(".probe-{:016x}.tmp", rand::random::<u64>()));
+    async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(&probe, b"").await?;
+        tokio::fs::remove_file(&probe).await
     }
+    .await
+    .with_context(|| format!("cannot write to {}", dir.display()))?;
+    Ok(Some(dir))
+}
 
-    async fn drop(mut self) {
-        let mut cache_locks = CACHE_LOCKS.lock().await;
-        let entry = unsafe { cache_locks.get_mut(&self.name).unwrap_unchecked() };
-        let name = take(&mut self.name);
-        drop(self);
-        entry.1 -= 1;
-        if entry.1 == 0 {
-            cache_locks.remove(&name);
-        }
+async fn read_cache(info_hash: &str) -> Result<Option<TorrentCache>> {
+    cache_store().get(info_hash).await
+}
+
+async fn write_cache(info_hash: &str, value: &TorrentCache) -> Result<()> {
+    cache_store().put(info_hash, value).await
+}
+
+/// Entries written by older versions key trackers by their percent-encoded host only.
+fn legacy_tracker_key(url: &Url) -> String {
+    utf8_percent_encode(url.host_str().unwrap_or_default(), NON_ALPHANUMERIC).to_string()
+}
+
+/// Identifies a refresh by the torrent, its tiers and the freshness bound. Joining a refresh of
+/// other tiers, or one which spared younger caches, could serve tiers the caller considers stale.
+fn refresh_key(
+    info_hash_encoded: &str,
+    tiers: &[Vec<TrackerRef>],
+    max_age: Option<Duration>,
+) -> String {
+    let trackers = tiers
+        .iter()
+        .map(|tier| {
+            let mut keys: Vec<_> = tier.iter().map(|tracker| tracker.key.as_str()).collect();
+            keys.sort_unstable();
+            keys.join(",")
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    match max_age {
+        Some(max_age) => format!("{info_hash_encoded}@{trackers}#{}", max_age.as_millis()),
+        None => format!("{info_hash_encoded}@{trackers}"),
     }
 }
 
-/// Clear overdue peers and fetch peer list from origin if needed.
-pub(crate) async fn fetch_cache(
-    tracker_url: String,
+/// Clear overdue peers and fetch peer list from origin if needed. If `max_age` is set, the cache
+/// is also refreshed when the last announce to this tracker is older than that.
+///
+/// `tiers` lists the origin trackers as in BEP 12. Usually there is only the tracker of the
+/// request, but a torrent transformed in multi-tracker mode carries all of its trackers, and a
+/// refresh announces to every tier of them.
+#[tracing::instrument(skip_all, fields(info_hash = %to_hex(info_hash)))]
+pub async fn fetch_cache(
+    tiers: Vec<Vec<String>>,
     info_hash: &[u8],
     size: Option<u64>,
     ttl: Duration,
+    max_age: Option<Duration>,
 ) -> Result<TorrentCache> {
-    let mut torrent_size = size;
-
-    let tracker_url_base_encoded = utf8_percent_encode(
-        Url::parse(&tracker_url)?.host_str().unwrap(),
-        NON_ALPHANUMERIC,
-    )
-    .to_string();
+    if is_blacklisted(info_hash) {
+        return Err(Blacklisted.into());
+    }
+    let tiers = tiers
+        .into_iter()
+        .map(|tier| {
+            tier.into_iter()
+                .map(|tracker_url| {
+                    let url = Url::parse(&tracker_url)?;
+                    Ok(TrackerRef {
+                        key: tracker::tracker_key(&url),
+                        legacy_key: legacy_tracker_key(&url),
+                        host: url.host_str().unwrap_or_default().to_lowercase(),
+                        url: tracker_url,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .filter(|tier| !tier.as_ref().is_ok_and(Vec::is_empty))
+        .collect::<Result<Vec<_>>>()?;
+    if tiers.is_empty() {
+        return Err(anyhow::anyhow!("no tracker to announce to"));
+    }
     let info_hash_encoded = percent_encode(info_hash, NON_ALPHANUMERIC).to_string();
 
     // If the cache is valid, simply return it.
-    let read_lock = CacheLockReadGuard::new(&info_hash_encoded).await;
+    let read_lock = CACHE_LOCKS.read(&info_hash_encoded).await;
     let curr_cache = read_cache(&info_hash_encoded).await?;
-    read_lock.drop().await;
-    if let Some(curr_cache) = curr_cache {
-        if let Some(&old_expiration) = curr_cache.trackers.get(&tracker_url_base_encoded)
-            && SystemTime::now() < old_expiration
-        {
+    drop(read_lock);
+    if let Some(mut curr_cache) = curr_cache {
+        curr_cache.migrate_tracker_keys(&tiers);
+        if curr_cache.is_fresh_for_tiers(&tiers, max_age) {
+            debug!("serving fresh cache");
+            Lookup::Hit.record();
+            record_access(&info_hash_encoded);
             return Ok(curr_cache);
         }
     };
 
+    // Concurrent misses for the same refresh share it, but each of them counts as a lookup of its
+    // own.
+    let key = refresh_key(&info_hash_encoded, &tiers, max_age);
+    let result = REFRESHES
+        .run(
+            &key,
+            refresh_cache(
+                tiers,
+                info_hash.to_vec(),
+                info_hash_encoded.clone(),
+                size,
+                ttl,
+                max_age,
+            ),
+        )
+        .await;
+    match result {
+        Ok((curr_cache, lookup)) => {
+            lookup.record();
+            record_access(&info_hash_encoded);
+            Ok(curr_cache)
+        }
+        Err(e) => {
+            Lookup::Miss.record();
+            Err(e)
+        }
+    }
+}
+
+/// Counts a request of a cached entry. Only entries which exist are counted, so that `info_hash`es
+/// nobody could fetch do not pile up in [`ACCESSES`].
+fn record_access(info_hash_encoded: &str) {
+    let now = SystemTime::now();
+    ACCESSES
+        .lock()
+        .unwrap()
+        .entry(info_hash_encoded.to_string())
+        .and_modify(|access| {
+            access.last = now;
+            access.count += 1;
+        })
+        .or_insert(Access {
+            last: now,
+            count: 1,
+        });
+}
+
+/// An origin tracker to announce to.
+struct TrackerRef {
+    url: String,
+    key: String,
+    legacy_key: String,
+    host: String,
+}
+
+/// Announce to origin and merge the result into the cache.
+#[tracing::instrument(name = "refresh", skip_all)]
+async fn refresh_cache(
+    tiers: Vec<Vec<TrackerRef>>,
+    info_hash: Vec<u8>,
+    info_hash_encoded: String,
+    size: Option<u64>,
+    ttl: Duration,
+    max_age: Option<Duration>,
+) -> Result<(TorrentCache, Lookup)> {
+    let mut torrent_size = size;
+
     // If the cache is invalid but flushed by another task, then also return it.
     // Here since we grab the write lock, there is no need to invoke further validation.
-    let write_lock = CacheLockWriteGuard::new(&info_hash_encoded).await;
-    let curr_cache = read_cache(&info_hash_encoded).await?;
-    if let Some(ref curr_cache) = curr_cache {
-        if let Some(&old_expiration) = curr_cache.trackers.get(&tracker_url_base_encoded)
-            && SystemTime::now() < old_expiration
-        {
-            write_lock.drop().await;
-            return Ok(curr_cache.clone());
+    let _write_lock = CACHE_LOCKS.write(&info_hash_encoded).await;
+    let mut curr_cache = read_cache(&info_hash_encoded).await?;
+    if let Some(ref mut curr_cache) = curr_cache {
+        curr_cache.migrate_tracker_keys(&tiers);
+        if curr_cache.is_fresh_for_tiers(&tiers, max_age) {
+            return Ok((curr_cache.clone(), Lookup::Hit));
         } else if torrent_size.is_none() {
             torrent_size = Some(curr_cache.size);
         }
     };
 
-    debug_assert!(torrent_size.is_some());
-    let torrent_size = torrent_size.unwrap();
-    let permit = timeout(Duration::from_secs(30), TRACKER_CONNECTIONS.acquire()).await??;
-    let tracker_response = timeout(
-        Duration::from_secs(20),
-        tracker::announce(&tracker_url, &info_hash, torrent_size),
-    )
-    .await??;
-    drop(permit);
+    // A panic here would poison the shared refresh for every waiter, so fail gracefully instead.
+    let torrent_size = torrent_size.ok_or(anyhow::anyhow!(
+        "torrent size is unknown before first announce"
+    ))?;
 
+    let stale = curr_cache.is_some();
     let mut curr_cache = curr_cache.unwrap_or_default();
     curr_cache.size = torrent_size;
-    // Respect the minimum announce interval from origin by keeping the cache valid for at least
-    // that long.
-    let ttl = if let Some(min_interval) = tracker_response.min_interval {
-        Duration::from_secs(min_interval).max(ttl)
+
+    // With nothing to serve meanwhile, wait for a busy origin as long as it takes. Otherwise,
+    // rather serve the stale cache than keep the client waiting.
+    let origin = &config().origin;
+    let patience = Duration::from_secs_f64(if stale {
+        origin.stale_wait
     } else {
-        ttl
+        origin.cold_wait
+    });
+
+    // Tiers refreshed recently, e.g. by a request naming only some of these trackers, are skipped.
+    let (fresh_tiers, stale_tiers): (Vec<_>, Vec<_>) = tiers
+        .into_iter()
+        .partition(|tier| curr_cache.is_fresh_for_tier(tier, max_age));
+
+    // As in BEP 12, each tier is shuffled and its trackers are tried in order until one of them
+    // responds. Unlike a client, we keep going with the next tier and merge all the results.
+    let mut last_error = None;
+    let mut announced = false;
+    for mut tier in stale_tiers {
+        tier.shuffle(&mut rand::rng());
+        for tracker in tier {
+            match announce_to_origin(&tracker, &info_hash, torrent_size, patience).await {
+                Ok(tracker_response) => {
+                    curr_cache.merge_announce(&tracker.key, tracker_response, ttl);
+                    announced = true;
+                    break;
+                }
+                Err(e) => {
+                    warn!(tracker = %tracker.key, "announce failed: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    if !announced && let Some(e) = last_error {
+        // Rather than failing, serve what we have while the origin is known to be down or busy, or
+        // if other tiers are fresh. Expired peers are kept, as they are still more likely to be
+        // around than no peers at all.
+        if !fresh_tiers.is_empty() || stale && (e.is::<CircuitOpen>() || e.is::<Throttled>()) {
+            info!("serving stale cache instead");
+            return Ok((curr_cache, Lookup::Stale));
+        }
+        return Err(e);
+    }
+    curr_cache.prune(SystemTime::now());
+    write_cache(&info_hash_encoded, &curr_cache).await?;
+
+    Ok((curr_cache, Lookup::Miss))
+}
+
+/// Announce to an origin tracker unless its circuit is open, retrying failures with exponential
+/// backoff. The delays are fully jittered so that retries of concurrent refreshes spread out.
+/// Waiting for [`ORIGIN_LIMITS`] longer than `patience` fails with [`Throttled`], which is never
+/// retried nor held against the tracker.
+#[tracing::instrument(skip_all, fields(tracker = %tracker.key))]
+async fn announce_to_origin(
+    tracker: &TrackerRef,
+    info_hash: &[u8],
+    size: u64,
+    patience: Duration,
+) -> Result<AnnounceResponse> {
+    const BACKOFF_BASE: Duration = Duration::from_millis(500);
+    const BACKOFF_CAP: Duration = Duration::from_secs(8);
+
+    let metrics = metrics();
+    let record = |outcome: &str| metrics.record_origin_announce(&tracker.host, outcome);
+
+    BREAKERS
+        .check(&tracker.key, Instant::now())
+        .inspect_err(|_| record("circuit_open"))?;
+    let retries = config().origin.retries;
+    let mut attempt = 0;
+    loop {
+        let waiting = Instant::now();
+        let permit = ORIGIN_LIMITS.acquire(&tracker.host, patience).await;
+        metrics.origin_wait.observe(waiting.elapsed().as_secs_f64());
+        let permit = permit.inspect_err(|_| record("throttled"))?;
+        let announcing = Instant::now();
+        let result = tracker::announce(&tracker.url, info_hash, size)
+            .await
+            .and_then(AnnounceResponse::accepted);
+        metrics
+            .origin_announce_duration
+            .observe(announcing.elapsed().as_secs_f64());
+        drop(permit);
+        record(if result.is_ok() { "success" } else { "failure" });
+        match result {
+            Err(e) if attempt < retries => {
+                let backoff = BACKOFF_BASE
+                    .saturating_mul(1 << attempt.min(16))
+                    .min(BACKOFF_CAP);
+                let backoff = rand::rng().random_range(Duration::ZERO..=backoff);
+                warn!(?backoff, "announce failed, retrying: {e:#}");
+                sleep(backoff).await;
+                attempt += 1;
+            }
+            result => {
+                BREAKERS.record(&tracker.key, &result, Instant::now());
+                return result;
+            }
+        }
+    }
+}
+
+/// Whether fetching failed only because the origin trackers are known to be down, and there is
+/// no cache to serve instead.
+pub fn is_circuit_open(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<SharedError>()
+        .map_or(e, SharedError::get)
+        .is::<CircuitOpen>()
+}
+
+/// Whether fetching failed only because announces to the origin trackers are limited, and there
+/// is no cache to serve instead.
+pub fn is_throttled(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<SharedError>()
+        .map_or(e, SharedError::get)
+        .is::<Throttled>()
+}
+
+/// Modify the cache of a torrent in place without contacting any origin tracker. Returns the
+/// updated cache, or `None` if the torrent has never been announced.
+pub async fn update_cache(
+    info_hash: &[u8],
+    f: impl FnOnce(&mut TorrentCache),
+) -> Result<Option<TorrentCache>> {
+    let info_hash_encoded = percent_encode(info_hash, NON_ALPHANUMERIC).to_string();
+
+    let _write_lock = CACHE_LOCKS.write(&info_hash_encoded).await;
+    let Some(mut curr_cache) = read_cache(&info_hash_encoded).await? else {
+        return Ok(None);
     };
-    curr_cache
-        .trackers
-        .insert(tracker_url_base_encoded.clone(), SystemTime::now() + ttl);
-    curr_cache
-        .peers_time
-        .extract_if(.., |&peer| peer.expire < SystemTime::now())
-        .for_each(|peer| {
-            curr_cache.peers_addr.remove(&peer.addr);
-        });
+    curr_cache.prune(SystemTime::now());
+    f(&mut curr_cache);
+    write_cache(&info_hash_encoded, &curr_cache).await?;
+
+    Ok(Some(curr_cache))
+}
+
+/// Lists the `info_hash` of every cached torrent.
+pub async fn list_entries() -> Result<Vec<Vec<u8>>> {
+    Ok(cache_store()
+        .list()
+        .await?
+        .into_iter()
+        .map(|x| percent_decode_str(&x).collect())
+        .collect())
+}
+
+/// Reads the cache of a torrent as is, without pruning nor contacting any origin tracker.
+pub async fn read_entry(info_hash: &[u8]) -> Result<Option<TorrentCache>> {
+    let info_hash_encoded = percent_encode(info_hash, NON_ALPHANUMERIC).to_string();
+    let _read_lock = CACHE_LOCKS.read(&info_hash_encoded).await;
+    read_cache(&info_hash_encoded).await
+}
+
+/// The torrents requested most often since startup, most requested first, along with the number
+/// of requests.
+pub fn hottest_entries(limit: usize) -> Vec<(Vec<u8>, u64)> {
+    let mut hottest: Vec<_> = ACCESSES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(info_hash, access)| (access.count, info_hash.clone()))
+        .collect();
+    hottest.sort_unstable_by(|a, b| b.cmp(a));
+    hottest
+        .into_iter()
+        .take(limit)
+        .map(|(count, info_hash)| (percent_decode_str(&info_hash).collect(), count))
+        .collect()
+}
+
+/// The circuit of every origin tracker which failed since it last succeeded, keyed by
+/// [`tracker::tracker_key`].
+pub fn circuit_states() -> Vec<(String, CircuitState)> {
+    BREAKERS.states(Instant::now())
+}
 
-    let mut new_peers = Vec::new();
-    if let Some(peers) = tracker_response.peers {
-        new_peers.extend(tracker::deserialize_peers_binary(&peers));
+/// Refreshes the cache of a torrent from `tracker_url`, or without it, expires the cache so that
+/// the next announce refreshes it, since passkeys are never stored. Returns the updated cache, or
+/// `None` if the torrent has never been announced.
+pub async fn refresh_entry(
+    info_hash: &[u8],
+    tracker_url: Option<String>,
+) -> Result<Option<TorrentCache>> {
+    match tracker_url {
+        Some(tracker_url) => {
+            if read_entry(info_hash).await?.is_none() {
+                return Ok(None);
+            }
+            let ttl = Duration::from_secs(config().server.default_ttl);
+            let tiers = vec![vec![tracker_url]];
+            Ok(Some(
+                fetch_cache(tiers, info_hash, None, ttl, Some(Duration::ZERO)).await?,
+            ))
+        }
+        None => {
+            update_cache(info_hash, |cache| {
+                let now = SystemTime::now();
+                for expiration in cache.trackers.values_mut() {
+                    *expiration = (*expiration).min(now);
+                }
+            })
+            .await
+        }
     }
-    if let Some(peers6) = tracker_response.peers6 {
-        new_peers.extend(tracker::deserialize_peers6_binary(&peers6));
+}
+
+/// Deletes the cache of a torrent. Returns whether there was any.
+pub async fn purge_entry(info_hash: &[u8]) -> Result<bool> {
+    let info_hash_encoded = percent_encode(info_hash, NON_ALPHANUMERIC).to_string();
+    let existed = read_entry(info_hash).await?.is_some();
+    gc::delete_entry(&info_hash_encoded, |_| false).await?;
+    Ok(existed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use url::Url;
+
+    use super::{TorrentCache, TrackerRef, refresh_key};
+    use crate::tracker::tracker_key;
+
+    fn key(tracker_url: &str) -> String {
+        tracker_key(&Url::parse(tracker_url).unwrap())
     }
-    let expiration = SystemTime::now() + ttl;
 
-    for addr in new_peers {
-        if let Some(entry_expiration) = curr_cache.peers_addr.get_mut(&addr) {
-            curr_cache.peers_time.remove(&Peer {
-                expire: *entry_expiration,
-                addr,
-            });
-            *entry_expiration = expiration;
-        } else {
-            curr_cache.peers_addr.insert(addr, expiration);
+    fn tracker(tracker_url: &str) -> TrackerRef {
+        TrackerRef {
+            url: tracker_url.to_string(),
+            key: key(tracker_url),
+            legacy_key: String::new(),
+            host: String::new(),
         }
-        curr_cache.peers_time.insert(Peer {
-            expire: expiration,
-            addr,
-        });
     }
-    write_cache(&info_hash_encoded, &curr_cache).await?;
 
-    write_lock.drop().await;
+    #[test]
+    fn checks_freshness_per_tier() {
+        let now = SystemTime::now();
+        let mut cache = TorrentCache::default();
+        let a = "https://a.example/announce";
+        let b = "https://b.example/announce";
+        let c = "https://c.example/announce";
+        cache
+            .trackers
+            .insert(key(a), now + Duration::from_secs(600));
+        cache
+            .trackers
+            .insert(key(c), now - Duration::from_secs(600));
+
+        assert!(cache.is_fresh_for_tiers(&[vec![tracker(a)]], None));
+        assert!(cache.is_fresh_for_tiers(&[vec![tracker(b), tracker(a)]], None));
+        assert!(!cache.is_fresh_for_tiers(&[vec![tracker(a)], vec![tracker(b)]], None));
+        assert!(!cache.is_fresh_for_tiers(&[vec![tracker(a)], vec![tracker(c)]], None));
+        assert!(!cache.is_fresh_for_tiers(&[vec![tracker(a)]], Some(Duration::from_secs(60))));
+    }
 
-    Ok(curr_cache)
+    #[test]
+    fn keys_refreshes_by_tiers_and_freshness() {
+        let a = || tracker("https://a.example/announce");
+        let b = || tracker("https://b.example/announce");
+        let key = |tiers: &[Vec<TrackerRef>], max_age| refresh_key("x", tiers, max_age);
+        assert_eq!(key(&[vec![a(), b()]], None), key(&[vec![b(), a()]], None));
+        let keys = [
+            key(&[vec![a()]], None),
+            key(&[vec![a(), b()]], None),
+            key(&[vec![a()], vec![b()]], None),
+            key(&[vec![a()]], Some(Duration::ZERO)),
+            key(&[vec![a()]], Some(Duration::from_secs(60))),
+        ];
+        for (i, x) in keys.iter().enumerate() {
+            for y in &keys[i + 1..] {
+                assert_ne!(x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_expired_trackers() {
+        let now = SystemTime::now();
+        let mut cache = TorrentCache::default();
+        let a = "https://a.example/announce";
+        let b = "https://b.example/announce";
+        let c = "https://c.example/announce";
+        cache
+            .trackers
+            .insert(key(a), now + Duration::from_secs(600));
+        cache
+            .trackers
+            .insert(key(b), now - Duration::from_secs(600));
+        cache.trackers.insert(key(c), now + Duration::from_secs(60));
+
+        let expiry = cache.nearest_expiry([a, b]);
+        assert!(expiry > Duration::from_secs(590) && expiry <= Duration::from_secs(600));
+        assert!(cache.nearest_expiry([a, c]) <= Duration::from_secs(60));
+        assert_eq!(cache.nearest_expiry([b]), Duration::ZERO);
+        assert_eq!(
+            cache.nearest_expiry(["https://d.example/announce"]),
+            Duration::ZERO
+        );
+    }
 }
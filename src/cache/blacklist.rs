@@ -0,0 +1,140 @@
+//! Torrents this service refuses to serve.
+//!
+//! The blacklist is small and changed rarely, so it is kept in memory, loaded once by
+//! [`super::init_cache_store`], and written back whole on every change to a hidden file in the
+//! cache directory, where the filesystem backend never mistakes it for an entry. It holds one
+//! `info_hash` per line in hex.
+
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{Context as _, Result};
+use tokio::io::AsyncWriteExt as _;
+
+use super::{get_cache_root_dir, purge_entry};
+use crate::metainfo::{parse_info_hash, to_hex};
+
+static BLACKLIST: Mutex<BTreeSet<Vec<u8>>> = Mutex::new(BTreeSet::new());
+/// Serializes writes of the file, so that an older list never overwrites a newer one.
+static SAVING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// The error of fetching a blacklisted torrent.
+#[derive(Debug)]
+pub struct Blacklisted;
+
+impl std::fmt::Display for Blacklisted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "torrent is not served by this tracker")
+    }
+}
+
+impl std::error::Error for Blacklisted {}
+
+fn blacklist_path() -> PathBuf {
+    get_cache_root_dir().join(".blacklist")
+}
+
+/// Parses the file, skipping blank lines and `#` comments.
+fn parse(text: &str) -> Result<BTreeSet<Vec<u8>>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_info_hash)
+        .collect()
+}
+
+pub(super) fn load_blacklist() -> Result<()> {
+    let path = blacklist_path();
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    *BLACKLIST.lock().unwrap_or_else(PoisonError::into_inner) =
+        parse(&text).with_context(|| format!("invalid blacklist {}", path.display()))?;
+    Ok(())
+}
+
+/// Writes the current list through a temporary file, like the filesystem backend writes entries.
+async fn save() -> Result<()> {
+    let _saving = SAVING.lock().await;
+    let text: String = list_blacklist()
+        .iter()
+        .map(|info_hash| format!("{}\n", to_hex(info_hash)))
+        .collect();
+    let path = blacklist_path();
+    let temp_path = path.with_extension("tmp");
+    async {
+        tokio::fs::create_dir_all(get_cache_root_dir()).await?;
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(text.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp_path, &path).await
+    }
+    .await
+    .with_context(|| format!("failed to write {}", path.display()))
+}
+
+pub fn is_blacklisted(info_hash: &[u8]) -> bool {
+    BLACKLIST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains(info_hash)
+}
+
+pub fn list_blacklist() -> Vec<Vec<u8>> {
+    BLACKLIST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Refuses to serve a torrent from now on, and deletes its cache. Returns whether it was not
+/// blacklisted yet.
+pub async fn blacklist_entry(info_hash: &[u8]) -> Result<bool> {
+    let added = BLACKLIST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(info_hash.to_vec());
+    if added {
+        save().await?;
+    }
+    purge_entry(info_hash).await?;
+    Ok(added)
+}
+
+/// Serves a torrent again. Returns whether it was blacklisted.
+pub async fn unblacklist_entry(info_hash: &[u8]) -> Result<bool> {
+    let removed = BLACKLIST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(info_hash);
+    if removed {
+        save().await?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn parses_blacklist() {
+        let blacklist = parse(
+            "# spam\n73f32593cd8e8e1b190b3c50263fff8df6033dfa\n\n  \
+             %12%34%56%78%9A%BC%DE%F0%12%34%56%78%9A%BC%DE%F0%12%34%56%78  \n",
+        )
+        .unwrap();
+        assert_eq!(blacklist.len(), 2);
+        assert!(
+            blacklist.contains(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0].repeat(3)[..20])
+        );
+        assert!(parse("73f32593cd8e8e1b190b3c50263fff8df6033d").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! Compact binary encoding of [`TorrentCache`] for storage.
+//!
+//! The layout starts with [`MAGIC`] followed by a schema version byte. All integers are
+//! big-endian and all times are milliseconds since the UNIX epoch. Version 3 is:
+//!
+//! ```text
+//! size: u64, downloaded: u64,
+//! trackers, announced, clients, completed: u32 count, then (u16 length, bytes, time: u64) each,
+//! sources: u32 count, then (u16 length, bytes) each,
+//! peers: u32 count, then (family: u8 = 4 | 6, address octets, port: u16, expire: u64,
+//!     provenance: u8 = 0 | 1, if 1 then (first_seen: u64, last_seen: u64,
+//!     u16 count, then u16 index into sources each)) each
+//! ```
+//!
+//! Version 2 lacks `completed`, and version 1 also `sources` and `provenance`. Both are still
+//! decoded. Entries whose strings or
+//! counts do not fit their length prefix are refused by [`encode`] rather than truncated.
+//!
+//! Unlike the in-memory representation, each peer is stored once; [`TorrentCache::peers_time`] is
+//! rebuilt on decoding. Entries written by older versions as JSON are recognized by their leading
+//! `{` and decoded transparently, so they migrate to the binary format on their next write.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use super::{Peer, PeerProvenance, TorrentCache};
+
+const MAGIC: &[u8] = b"PTC";
+const VERSION: u8 = 3;
+
+fn to_millis(value: SystemTime) -> u64 {
+    value
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn from_millis(value: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(value)
+}
+
+fn to_u16(value: usize, what: &str) -> Result<u16> {
+    u16::try_from(value).map_err(|_| anyhow::anyhow!("too many {what} for a cache entry: {value}"))
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) -> Result<()> {
+    buf.extend(to_u16(value.len(), "bytes in a string")?.to_be_bytes());
+    buf.extend(value.as_bytes());
+    Ok(())
+}
+
+fn put_map(buf: &mut Vec<u8>, map: &HashMap<String, SystemTime>) -> Result<()> {
+    buf.extend((map.len() as u32).to_be_bytes());
+    for (key, &time) in map {
+        put_str(buf, key)?;
+        buf.extend(to_millis(time).to_be_bytes());
+    }
+    Ok(())
+}
+
+pub(crate) fn encode(value: &TorrentCache) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(64 + value.peers_addr.len() * 32);
+    buf.extend(MAGIC);
+    buf.push(VERSION);
+    buf.extend(value.size.to_be_bytes());
+    buf.extend(value.downloaded.to_be_bytes());
+    put_map(&mut buf, &value.trackers)?;
+    put_map(&mut buf, &value.announced)?;
+    put_map(&mut buf, &value.clients)?;
+    put_map(&mut buf, &value.completed)?;
+
+    let sources: BTreeSet<&str> = value
+        .peer_sources
+        .values()
+        .flat_map(|x| x.trackers.iter().map(String::as_str))
+        .collect();
+    buf.extend((sources.len() as u32).to_be_bytes());
+    for source in &sources {
+        put_str(&mut buf, source)?;
+    }
+    let sources: HashMap<&str, u16> = sources
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| Ok((x, to_u16(i, "peer sources")?)))
+        .collect::<Result<_>>()?;
+
+    buf.extend((value.peers_addr.len() as u32).to_be_bytes());
+    for (addr, &expire) in &value.peers_addr {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(4);
+                buf.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(6);
+                buf.extend(ip.octets());
+            }
+        }
+        buf.extend(addr.port().to_be_bytes());
+        buf.extend(to_millis(expire).to_be_bytes());
+        if let Some(provenance) = value.peer_sources.get(addr) {
+            buf.push(1);
+            buf.extend(to_millis(provenance.first_seen).to_be_bytes());
+            buf.extend(to_millis(provenance.last_seen).to_be_bytes());
+            buf.extend(to_u16(provenance.trackers.len(), "peer sources")?.to_be_bytes());
+            for tracker in &provenance.trackers {
+                buf.extend(sources[tracker.as_str()].to_be_bytes());
+            }
+        } else {
+            buf.push(0);
+        }
+    }
+    Ok(buf)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(anyhow::anyhow!("unexpected end of cache entry"));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+
+    fn time(&mut self) -> Result<SystemTime> {
+        Ok(from_millis(self.u64()?))
+    }
+
+    fn map(&mut self) -> Result<HashMap<String, SystemTime>> {
+        let len = self.u32()?;
+        let mut map = HashMap::new();
+        for _ in 0..len {
+            let key = self.str()?;
+            map.insert(key, self.time()?);
+        }
+        Ok(map)
+    }
+}
+
+pub(crate) fn decode(value: &[u8]) -> Result<TorrentCache> {
+    if value.first() == Some(&b'{') {
+        return Ok(serde_json::from_slice(value)?);
+    }
+
+    let mut reader = Reader { buf: value };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(anyhow::anyhow!("not a cache entry"));
+    }
+    let version = reader.u8()?;
+    if !(1..=VERSION).contains(&version) {
+        return Err(anyhow::anyhow!(
+            "unsupported cache schema version {}",
+            version
+        ));
+    }
+
+    let mut cache = TorrentCache {
+        size: reader.u64()?,
+        downloaded: reader.u64()?,
+        trackers: reader.map()?,
+        announced: reader.map()?,
+        clients: reader.map()?,
+        completed: if version >= 3 {
+            reader.map()?
+        } else {
+            HashMap::new()
+        },
+        ..Default::default()
+    };
+    let mut sources = Vec::new();
+    if version >= 2 {
+        for _ in 0..reader.u32()? {
+            sources.push(reader.str()?);
+        }
+    }
+    let peers_len = reader.u32()?;
+    for _ in 0..peers_len {
+        let ip: IpAddr = match reader.u8()? {
+            4 => Ipv4Addr::from(reader.array::<4>()?).into(),
+            6 => Ipv6Addr::from(reader.array::<16>()?).into(),
+            family => return Err(anyhow::anyhow!("unknown address family {}", family)),
+        };
+        let addr = SocketAddr::new(ip, reader.u16()?);
+        let expire = reader.time()?;
+        cache.peers_addr.insert(addr, expire);
+        cache.peers_time.insert(Peer { expire, addr });
+        if version >= 2 && reader.u8()? == 1 {
+            let first_seen = reader.time()?;
+            let last_seen = reader.time()?;
+            let mut trackers = BTreeSet::new();
+            for _ in 0..reader.u16()? {
+                let source = sources
+                    .get(reader.u16()? as usize)
+                    .ok_or(anyhow::anyhow!("peer source out of range"))?;
+                trackers.insert(source.clone());
+            }
+            cache.peer_sources.insert(
+                addr,
+                PeerProvenance {
+                    trackers,
+                    first_seen,
+                    last_seen,
+                },
+            );
+        }
+    }
+    if !reader.buf.is_empty() {
+        return Err(anyhow::anyhow!("trailing bytes after cache entry"));
+    }
+
+    Ok(cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::{decode, encode};
+    use crate::cache::{Peer, PeerProvenance, TorrentCache};
+
+    fn sample() -> TorrentCache {
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let mut cache = TorrentCache {
+            size: 1234,
+            downloaded: 5,
+            ..Default::default()
+        };
+        cache.trackers.insert("a.example".to_string(), now);
+        cache.clients.insert("%2DqB5120%2D".to_string(), now);
+        cache.completed.insert("%2DqB5120%2D".to_string(), now);
+        for (i, addr) in ["1.2.3.4:5", "[::1]:6881"].into_iter().enumerate() {
+            let addr = addr.parse().unwrap();
+            cache.peers_addr.insert(addr, now);
+            cache.peers_time.insert(Peer { expire: now, addr });
+            if i == 0 {
+                cache.peer_sources.insert(
+                    addr,
+                    PeerProvenance {
+                        trackers: BTreeSet::from(["a.example".to_string()]),
+                        first_seen: now,
+                        last_seen: now,
+                    },
+                );
+            }
+        }
+        cache
+    }
+
+    #[test]
+    fn roundtrip() {
+        let cache = sample();
+        let decoded = decode(&encode(&cache).unwrap()).unwrap();
+        assert_eq!(decoded.size, cache.size);
+        assert_eq!(decoded.downloaded, cache.downloaded);
+        assert_eq!(decoded.trackers, cache.trackers);
+        assert_eq!(decoded.clients, cache.clients);
+        assert_eq!(decoded.completed, cache.completed);
+        assert_eq!(decoded.peers_addr, cache.peers_addr);
+        assert!(decoded.peers_time == cache.peers_time);
+        assert_eq!(decoded.peer_sources.len(), 1);
+        assert_eq!(
+            decoded.peer_sources.values().next().unwrap().trackers,
+            cache.peer_sources.values().next().unwrap().trackers
+        );
+    }
+
+    #[test]
+    fn refuses_overlong_strings() {
+        let mut cache = sample();
+        cache
+            .clients
+            .insert("x".repeat(u16::MAX as usize), UNIX_EPOCH);
+        let decoded = decode(&encode(&cache).unwrap()).unwrap();
+        assert_eq!(decoded.clients, cache.clients);
+
+        cache
+            .clients
+            .insert("x".repeat(u16::MAX as usize + 1), UNIX_EPOCH);
+        assert!(encode(&cache).is_err());
+    }
+
+    #[test]
+    fn reads_legacy_json() {
+        let cache = sample();
+        let decoded = decode(&serde_json::to_vec(&cache).unwrap()).unwrap();
+        assert_eq!(decoded.peers_addr, cache.peers_addr);
+    }
+
+    #[test]
+    fn reads_version_2() {
+        let empty = [b"PTC\x02".as_slice(), &[0; 16], &[0; 20]].concat();
+        let decoded = decode(&empty).unwrap();
+        assert!(decoded.clients.is_empty() && decoded.completed.is_empty());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode(b"").is_err());
+        assert!(decode(b"PTC\xff").is_err());
+        let mut truncated = encode(&sample()).unwrap();
+        truncated.pop();
+        assert!(decode(&truncated).is_err());
+    }
+}
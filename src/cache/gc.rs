@@ -0,0 +1,142 @@
+//! Periodic garbage collection of the cache store.
+//!
+//! Peers only expire lazily when a torrent is announced again, so entries of torrents nobody asks
+//! for anymore would otherwise stay forever. Each sweep drops expired peers, deletes entries that
+//! have been fully expired for longer than a grace period, and then evicts the least recently
+//! used entries until the store fits into the configured quota. The resulting number and size of
+//! entries are reported to [`crate::metrics`].
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use tracing::{debug, error, info};
+
+use super::{ACCESSES, CACHE_LOCKS, TorrentCache, cache_store, store::CacheStore as _};
+use crate::{config::config, metrics::metrics};
+
+/// Runs a sweep every `gc_interval` seconds, until `shutdown` completes. A sweep in progress is
+/// finished first.
+pub async fn run_garbage_collector(shutdown: impl Future<Output = ()>) {
+    let period = Duration::from_secs(config().cache.gc_interval);
+    let mut interval = tokio::time::interval(period);
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            () = &mut shutdown => break,
+        }
+        if let Err(e) = collect_garbage().await {
+            error!("garbage collection failed: {e:#}");
+        }
+    }
+}
+
+/// Deletes an entry under its write lock, unless `keep` decides otherwise after re-reading it.
+pub(super) async fn delete_entry(
+    info_hash: &str,
+    keep: impl FnOnce(&TorrentCache) -> bool,
+) -> Result<bool> {
+    let _write_lock = CACHE_LOCKS.write(info_hash).await;
+    match cache_store().get(info_hash).await? {
+        Some(cache) if keep(&cache) => Ok(false),
+        _ => {
+            cache_store().delete(info_hash).await?;
+            ACCESSES.lock().unwrap().remove(info_hash);
+            Ok(true)
+        }
+    }
+}
+
+#[tracing::instrument(name = "gc", skip_all)]
+pub async fn collect_garbage() -> Result<()> {
+    let now = SystemTime::now();
+    let grace = Duration::from_secs(config().cache.gc_grace);
+    let max_entries = config().cache.max_entries;
+    let max_bytes = config().cache.max_bytes;
+
+    // Entries that have been useless for a while. The expiration is checked again under the lock
+    // since the entry may have been refreshed in the meantime.
+    let mut expired = 0;
+    for info_hash in cache_store().scan_expired(now - grace).await? {
+        if delete_entry(&info_hash, |cache| cache.expiration() + grace >= now).await? {
+            expired += 1;
+        }
+    }
+    debug!(expired, "deleted expired entries");
+
+    // Expired peers and clients of the remaining entries. Entries are read and written around the
+    // hot tier, so that a sweep does not evict the entries actually in use.
+    let mut pruned = 0;
+    for info_hash in cache_store().scan_prunable(now).await? {
+        let _write_lock = CACHE_LOCKS.write(&info_hash).await;
+        if let Some(mut cache) = cache_store().peek(&info_hash).await?
+            && cache.prune(now)
+        {
+            cache_store().put_quietly(&info_hash, &cache).await?;
+            pruned += 1;
+        }
+    }
+    debug!(pruned, "pruned expired peers");
+
+    // The usage is needed for the quota anyway, and keeps the metrics of the cache up to date.
+    let usage: HashMap<_, _> = cache_store().usage().await?.into_iter().collect();
+    // Accesses of entries deleted behind our back, e.g. by another instance, are not needed.
+    ACCESSES
+        .lock()
+        .unwrap()
+        .retain(|info_hash, _| usage.contains_key(info_hash));
+    let mut entries = usage.len() as u64;
+    let mut bytes: u64 = usage.values().sum();
+    let over_quota = |entries, bytes| {
+        entries > max_entries.unwrap_or(u64::MAX) || bytes > max_bytes.unwrap_or(u64::MAX)
+    };
+
+    // The last use of each entry for LRU eviction, falling back to its last announce for entries
+    // untouched since startup. Only collected when needed, as it may read every entry.
+    let mut last_used = Vec::new();
+    let candidates = if over_quota(entries, bytes) {
+        usage.keys().collect()
+    } else {
+        Vec::new()
+    };
+    for info_hash in candidates {
+        let last_access = ACCESSES
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .map(|access| access.last);
+        let last_access = match last_access {
+            Some(last_access) => last_access,
+            None => {
+                let _read_lock = CACHE_LOCKS.read(info_hash).await;
+                match cache_store().peek(info_hash).await? {
+                    Some(cache) => cache.last_announced(),
+                    None => continue,
+                }
+            }
+        };
+        last_used.push((last_access, info_hash.clone()));
+    }
+    let mut evicted = 0;
+    last_used.sort();
+    for (_, info_hash) in last_used {
+        if !over_quota(entries, bytes) {
+            break;
+        }
+        if delete_entry(&info_hash, |_| false).await? {
+            entries -= 1;
+            bytes = bytes.saturating_sub(usage.get(&info_hash).copied().unwrap_or(0));
+            evicted += 1;
+        }
+    }
+    if evicted > 0 {
+        info!(evicted, entries, bytes, "evicted entries over quota");
+    }
+    metrics().cache_entries.set(entries as i64);
+    metrics().cache_bytes.set(bytes as i64);
+
+    Ok(())
+}
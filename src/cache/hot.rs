@@ -0,0 +1,118 @@
+//! In-process LRU tier in front of the cache store.
+//!
+//! Reading an entry from the store means touching the disk and parsing the whole entry, even
+//! when it is valid and unchanged. This tier keeps the most recently used entries in memory and
+//! writes through to the store. It relies on the same per-hash locks as the store: every `put`
+//! and `delete` happens under the write lock in [`super::CACHE_LOCKS`], so readers never observe
+//! the tier and the store disagreeing.
+
+use std::{num::NonZeroUsize, sync::Mutex, time::SystemTime};
+
+use anyhow::Result;
+use lru::LruCache;
+
+use super::{TorrentCache, store::CacheStore};
+
+pub struct HotTier<S> {
+    inner: S,
+    /// `None` if the tier is disabled.
+    entries: Option<Mutex<LruCache<String, TorrentCache>>>,
+}
+
+impl<S> HotTier<S> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            entries: NonZeroUsize::new(capacity).map(|x| Mutex::new(LruCache::new(x))),
+        }
+    }
+}
+
+impl<S: CacheStore> HotTier<S> {
+    /// Reads an entry without promoting nor inserting it, for sweeps over many entries which
+    /// would otherwise evict the ones actually in use.
+    pub(super) async fn peek(&self, info_hash: &str) -> Result<Option<TorrentCache>> {
+        if let Some(entries) = &self.entries
+            && let Some(cache) = entries.lock().unwrap().peek(info_hash)
+        {
+            return Ok(Some(cache.clone()));
+        }
+        self.inner.get(info_hash).await
+    }
+
+    /// Writes an entry through, updating the copy in memory only if there is one, and without
+    /// promoting it.
+    pub(super) async fn put_quietly(&self, info_hash: &str, value: &TorrentCache) -> Result<()> {
+        let result = self.inner.put(info_hash, value).await;
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            if result.is_err() {
+                entries.pop(info_hash);
+            } else if let Some(cache) = entries.peek_mut(info_hash) {
+                *cache = value.clone();
+            }
+        }
+        result
+    }
+}
+
+impl<S: CacheStore> CacheStore for HotTier<S> {
+    async fn get(&self, info_hash: &str) -> Result<Option<TorrentCache>> {
+        let Some(entries) = &self.entries else {
+            return self.inner.get(info_hash).await;
+        };
+        if let Some(cache) = entries.lock().unwrap().get(info_hash) {
+            return Ok(Some(cache.clone()));
+        }
+        let cache = self.inner.get(info_hash).await?;
+        if let Some(cache) = &cache {
+            entries
+                .lock()
+                .unwrap()
+                .put(info_hash.to_string(), cache.clone());
+        }
+        Ok(cache)
+    }
+
+    async fn put(&self, info_hash: &str, value: &TorrentCache) -> Result<()> {
+        let result = self.inner.put(info_hash, value).await;
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            // Never keep a copy the store failed to persist.
+            if result.is_ok() {
+                entries.put(info_hash.to_string(), value.clone());
+            } else {
+                entries.pop(info_hash);
+            }
+        }
+        result
+    }
+
+    async fn delete(&self, info_hash: &str) -> Result<()> {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().pop(info_hash);
+        }
+        self.inner.delete(info_hash).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.inner.list().await
+    }
+
+    async fn usage(&self) -> Result<Vec<(String, u64)>> {
+        self.inner.usage().await
+    }
+
+    async fn scan_expired(&self, before: SystemTime) -> Result<Vec<String>> {
+        self.inner.scan_expired(before).await
+    }
+
+    async fn scan_prunable(&self, before: SystemTime) -> Result<Vec<String>> {
+        self.inner.scan_prunable(before).await
+    }
+
+    /// Entries are written through, so there is nothing to flush here but the store.
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
@@ -0,0 +1,186 @@
+//! Read-write locks keyed by string, created on demand.
+//!
+//! Each key maps to a lock and the number of tickets referring to it. A ticket is taken before
+//! waiting for the lock and returned when the guard, or the waiting future, is dropped. The entry
+//! is removed once the last ticket is returned, so the map only holds keys that are in use. Since
+//! both happen in [`Drop`], early returns, panics and cancelled futures all release correctly.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+use crate::metrics::metrics;
+
+type Entry = (Arc<RwLock<()>>, usize);
+
+#[derive(Default)]
+pub(crate) struct KeyedLock {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// A reference to an entry of [`KeyedLock`] which keeps the entry alive.
+struct Ticket<'a> {
+    owner: &'a KeyedLock,
+    key: String,
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.owner.entries();
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                entries.remove(&self.key);
+            }
+        }
+    }
+}
+
+pub(crate) struct KeyedReadGuard<'a> {
+    _inner: OwnedRwLockReadGuard<()>,
+    _ticket: Ticket<'a>,
+}
+
+pub(crate) struct KeyedWriteGuard<'a> {
+    _inner: OwnedRwLockWriteGuard<()>,
+    _ticket: Ticket<'a>,
+}
+
+/// Records how long it took to get a lock, which is how contended it is.
+fn record_wait(mode: &str, since: Instant) {
+    metrics()
+        .lock_wait
+        .with_label_values(&[mode])
+        .observe(since.elapsed().as_secs_f64());
+}
+
+impl KeyedLock {
+    /// Nothing panics while the mutex is held, but a poisoned map is still consistent anyway.
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn ticket(&self, key: &str) -> (Arc<RwLock<()>>, Ticket<'_>) {
+        let mut entries = self.entries();
+        let entry = entries.entry(key.to_string()).or_default();
+        entry.1 += 1;
+        (
+            entry.0.clone(),
+            Ticket {
+                owner: self,
+                key: key.to_string(),
+            },
+        )
+    }
+
+    pub(crate) async fn read(&self, key: &str) -> KeyedReadGuard<'_> {
+        let (lock, ticket) = self.ticket(key);
+        let waiting = Instant::now();
+        let guard = lock.read_owned().await;
+        record_wait("read", waiting);
+        KeyedReadGuard {
+            _inner: guard,
+            _ticket: ticket,
+        }
+    }
+
+    pub(crate) async fn write(&self, key: &str) -> KeyedWriteGuard<'_> {
+        let (lock, ticket) = self.ticket(key);
+        let waiting = Instant::now();
+        let guard = lock.write_owned().await;
+        record_wait("write", waiting);
+        KeyedWriteGuard {
+            _inner: guard,
+            _ticket: ticket,
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use anyhow::Result;
+    use tokio::time::timeout;
+
+    use super::KeyedLock;
+
+    #[tokio::test]
+    async fn releases_on_drop() {
+        let locks = KeyedLock::default();
+        let a = locks.read("a").await;
+        let b = locks.read("a").await;
+        let c = locks.write("c").await;
+        assert_eq!(locks.len(), 2);
+        drop(a);
+        assert_eq!(locks.len(), 2);
+        drop((b, c));
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn releases_on_error() {
+        async fn fails(locks: &KeyedLock) -> Result<()> {
+            let _guard = locks.write("a").await;
+            Err(anyhow::anyhow!("origin timed out"))?;
+            unreachable!();
+        }
+
+        let locks = KeyedLock::default();
+        assert!(fails(&locks).await.is_err());
+        assert_eq!(locks.len(), 0);
+        assert!(
+            timeout(Duration::from_millis(100), locks.write("a"))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn releases_on_cancellation() {
+        let locks = KeyedLock::default();
+        let writer = locks.write("a").await;
+        assert!(
+            timeout(Duration::from_millis(50), locks.read("a"))
+                .await
+                .is_err()
+        );
+        assert!(
+            timeout(Duration::from_millis(50), locks.write("a"))
+                .await
+                .is_err()
+        );
+        assert_eq!(locks.len(), 1);
+        drop(writer);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn releases_on_panic() {
+        let locks = Arc::new(KeyedLock::default());
+        let result = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                let _guard = locks.write("a").await;
+                panic!("cache corrupted");
+            }
+        })
+        .await;
+        assert!(result.unwrap_err().is_panic());
+        assert_eq!(locks.len(), 0);
+        assert!(
+            timeout(Duration::from_millis(100), locks.read("a"))
+                .await
+                .is_ok()
+        );
+    }
+}
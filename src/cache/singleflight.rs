@@ -0,0 +1,207 @@
+//! Deduplication of concurrent calls by key.
+//!
+//! The first caller for a key starts the call, and everyone arriving while it is in flight awaits
+//! the same future and receives a clone of its result, errors included. The call runs in a task of
+//! its own, so it completes even if every waiter is cancelled, and never lingers while holding
+//! cache locks.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::Result;
+use futures::{
+    FutureExt as _,
+    future::{BoxFuture, Shared},
+};
+
+type Call<T> = Shared<BoxFuture<'static, Result<T, Arc<anyhow::Error>>>>;
+
+/// The error of a call, as seen by each of its waiters.
+#[derive(Debug)]
+pub(crate) struct SharedError(Arc<anyhow::Error>);
+
+impl SharedError {
+    pub(crate) fn get(&self) -> &anyhow::Error {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for SharedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {}
+
+/// In-flight calls by key, along with an ID telling apart successive calls for the same key.
+type Calls<T> = Mutex<HashMap<String, (u64, Call<T>)>>;
+
+pub(crate) struct Singleflight<T> {
+    calls: Arc<Calls<T>>,
+    next_id: AtomicU64,
+}
+
+impl<T> Default for Singleflight<T> {
+    fn default() -> Self {
+        Self {
+            calls: Default::default(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Singleflight<T> {
+    /// Runs `f`, unless a call for `key` is already in flight, in which case `f` is dropped and
+    /// the result of that call is returned instead.
+    pub(crate) async fn run(
+        &self,
+        key: &str,
+        f: impl Future<Output = Result<T>> + Send + 'static,
+    ) -> Result<T> {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(key) {
+                Some((_, call)) => call.clone(),
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    // The task removes its own call once done, which cannot happen before the
+                    // call is inserted since the map stays locked until then.
+                    let task = tokio::spawn({
+                        let calls = self.calls.clone();
+                        let key = key.to_string();
+                        async move {
+                            let result = f.await.map_err(Arc::new);
+                            let mut calls = calls.lock().unwrap();
+                            if calls.get(&key).is_some_and(|&(x, _)| x == id) {
+                                calls.remove(&key);
+                            }
+                            result
+                        }
+                    });
+                    let call = task
+                        .map(|result| {
+                            result.unwrap_or_else(|e| {
+                                Err(Arc::new(anyhow::anyhow!("call failed: {e}")))
+                            })
+                        })
+                        .boxed()
+                        .shared();
+                    calls.insert(key.to_string(), (id, call.clone()));
+                    call
+                }
+            }
+        };
+
+        call.await.map_err(|e| SharedError(e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::Singleflight;
+
+    #[tokio::test]
+    async fn coalesces_concurrent_calls() {
+        let group = Arc::new(Singleflight::default());
+        let started = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let group = group.clone();
+                let started = started.clone();
+                tokio::spawn(async move {
+                    group
+                        .run("a", async move {
+                            started.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok(42)
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), 42);
+        }
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert!(group.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shares_errors() {
+        let group = Arc::new(Singleflight::<()>::default());
+        let follower = tokio::spawn({
+            let group = group.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                group.run("a", async { Ok(()) }).await
+            }
+        });
+        let leader = group.run("a", async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Err(anyhow::anyhow!("tracker is down"))
+        });
+        assert_eq!(leader.await.unwrap_err().to_string(), "tracker is down");
+        assert_eq!(
+            follower.await.unwrap().unwrap_err().to_string(),
+            "tracker is down"
+        );
+    }
+
+    #[tokio::test]
+    async fn survives_cancelled_leader() {
+        let group = Arc::new(Singleflight::default());
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(1)
+        };
+        let leader = tokio::spawn({
+            let group = group.clone();
+            async move { group.run("a", slow()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = tokio::spawn({
+            let group = group.clone();
+            async move { group.run("a", async { Ok(2) }).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        leader.abort();
+        assert_eq!(follower.await.unwrap().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn completes_without_waiters() {
+        let group = Singleflight::default();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let lonely = group.run("a", {
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+                Ok(1)
+            }
+        });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), lonely)
+                .await
+                .is_err()
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert!(group.calls.lock().unwrap().is_empty());
+        assert_eq!(group.run("a", async { Ok(2) }).await.unwrap(), 2);
+    }
+}
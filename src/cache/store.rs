@@ -0,0 +1,125 @@
+//! Storage backends for [`TorrentCache`] entries.
+//!
+//! Every backend is keyed by percent-encoded `info_hash`. Locking is not the concern of this
+//! module: callers must hold the per-hash cache lock when reading or writing an entry.
+
+mod filesystem;
+mod memory;
+mod sqlite;
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+pub use self::{filesystem::FilesystemStore, memory::MemoryStore, sqlite::SqliteStore};
+use super::TorrentCache;
+use crate::config::CacheBackendKind;
+
+/// The futures of every backend here are `Send`, which only holds for callers that know the
+/// concrete type. Implementations outside of this crate are on their own.
+#[allow(async_fn_in_trait)]
+pub trait CacheStore {
+    async fn get(&self, info_hash: &str) -> Result<Option<TorrentCache>>;
+    async fn put(&self, info_hash: &str, value: &TorrentCache) -> Result<()>;
+    async fn delete(&self, info_hash: &str) -> Result<()>;
+    async fn list(&self) -> Result<Vec<String>>;
+    /// Lists entries along with the storage space in bytes they take.
+    async fn usage(&self) -> Result<Vec<(String, u64)>>;
+    /// Lists entries whose trackers, peers and clients all expired before `before`.
+    async fn scan_expired(&self, before: SystemTime) -> Result<Vec<String>>;
+    /// Lists entries with peers or clients which expired before `before`, i.e. whose
+    /// [`TorrentCache::next_expiry`] is earlier.
+    async fn scan_prunable(&self, before: SystemTime) -> Result<Vec<String>>;
+    /// Persists whatever the backend still keeps in memory, before the process exits.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The backend selected by the configuration. Native `async fn` in traits are not object-safe,
+/// so the dispatch is done by hand.
+pub enum CacheBackend {
+    Filesystem(FilesystemStore),
+    Sqlite(SqliteStore),
+    Memory(MemoryStore),
+}
+
+impl CacheBackend {
+    pub fn open(kind: CacheBackendKind) -> Result<Self> {
+        match kind {
+            CacheBackendKind::Filesystem => Ok(Self::Filesystem(FilesystemStore::new(
+                super::get_cache_root_dir(),
+            ))),
+            CacheBackendKind::Sqlite => Ok(Self::Sqlite(SqliteStore::open(
+                &super::get_cache_root_dir().with_extension("sqlite3"),
+            )?)),
+            CacheBackendKind::Memory => Ok(Self::Memory(MemoryStore::default())),
+        }
+    }
+}
+
+impl CacheStore for CacheBackend {
+    async fn get(&self, info_hash: &str) -> Result<Option<TorrentCache>> {
+        match self {
+            Self::Filesystem(store) => store.get(info_hash).await,
+            Self::Sqlite(store) => store.get(info_hash).await,
+            Self::Memory(store) => store.get(info_hash).await,
+        }
+    }
+
+    async fn put(&self, info_hash: &str, value: &TorrentCache) -> Result<()> {
+        match self {
+            Self::Filesystem(store) => store.put(info_hash, value).await,
+            Self::Sqlite(store) => store.put(info_hash, value).await,
+            Self::Memory(store) => store.put(info_hash, value).await,
+        }
+    }
+
+    async fn delete(&self, info_hash: &str) -> Result<()> {
+        match self {
+            Self::Filesystem(store) => store.delete(info_hash).await,
+            Self::Sqlite(store) => store.delete(info_hash).await,
+            Self::Memory(store) => store.delete(info_hash).await,
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        match self {
+            Self::Filesystem(store) => store.list().await,
+            Self::Sqlite(store) => store.list().await,
+            Self::Memory(store) => store.list().await,
+        }
+    }
+
+    async fn usage(&self) -> Result<Vec<(String, u64)>> {
+        match self {
+            Self::Filesystem(store) => store.usage().await,
+            Self::Sqlite(store) => store.usage().await,
+            Self::Memory(store) => store.usage().await,
+        }
+    }
+
+    async fn scan_expired(&self, before: SystemTime) -> Result<Vec<String>> {
+        match self {
+            Self::Filesystem(store) => store.scan_expired(before).await,
+            Self::Sqlite(store) => store.scan_expired(before).await,
+            Self::Memory(store) => store.scan_expired(before).await,
+        }
+    }
+
+    async fn scan_prunable(&self, before: SystemTime) -> Result<Vec<String>> {
+        match self {
+            Self::Filesystem(store) => store.scan_prunable(before).await,
+            Self::Sqlite(store) => store.scan_prunable(before).await,
+            Self::Memory(store) => store.scan_prunable(before).await,
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        match self {
+            Self::Filesystem(store) => store.flush().await,
+            Self::Sqlite(store) => store.flush().await,
+            Self::Memory(store) => store.flush().await,
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! One file per torrent under the cache directory.
+//!
+//! Writes go to a hidden temporary file which is synced and then renamed over the entry, so a
+//! crash or a full disk never leaves a half-written entry behind. Entries that fail to parse
+//! anyway (e.g. written by an older version without this guarantee) are moved into
+//! [`QUARANTINE_DIR`] for inspection and treated as a cache miss. Garbage collection removes
+//! temporary files left behind by interrupted writes, and quarantined entries once they are older
+//! than [`QUARANTINE_RETENTION`].
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use tokio::{
+    fs::create_dir_all,
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+};
+use tracing::warn;
+
+use super::CacheStore;
+use crate::cache::{TorrentCache, codec};
+
+const QUARANTINE_DIR: &str = ".quarantine";
+const QUARANTINE_RETENTION: Duration = Duration::from_secs(7 * 86400);
+/// Temporary files are only written for as long as a single entry takes, so older ones are
+/// leftovers.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(3600);
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Lists the files of a directory along with when they were last modified. A missing directory
+/// has none.
+async fn list_files(dir: &Path) -> Result<Vec<(String, SystemTime)>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut result = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        // Skip files removed in the meantime.
+        if let Ok(metadata) = entry.metadata().await
+            && metadata.is_file()
+            && let Ok(name) = entry.file_name().into_string()
+        {
+            result.push((name, metadata.modified()?));
+        }
+    }
+    Ok(result)
+}
+
+pub struct FilesystemStore {
+    root: PathBuf,
+}
+
+impl FilesystemStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn temp_path(&self, info_hash: &str) -> PathBuf {
+        // Percent-encoded `info_hash`'es never start with a dot.
+        self.root.join(format!(".{info_hash}.tmp"))
+    }
+
+    async fn quarantine(&self, info_hash: &str) -> Result<()> {
+        let quarantine_dir = self.root.join(QUARANTINE_DIR);
+        create_dir_all(&quarantine_dir).await?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match tokio::fs::rename(
+            self.root.join(info_hash),
+            quarantine_dir.join(format!("{info_hash}.{timestamp}")),
+        )
+        .await
+        {
+            // Another reader quarantined it first.
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes temporary files older than [`STALE_TEMP_AGE`] and quarantined entries older than
+    /// [`QUARANTINE_RETENTION`] at `now`.
+    async fn remove_leftovers(&self, now: SystemTime) -> Result<()> {
+        let older = |time: SystemTime, age| now.duration_since(time).is_ok_and(|x| x > age);
+        for (name, modified) in list_files(&self.root).await? {
+            if name.starts_with('.') && name.ends_with(".tmp") && older(modified, STALE_TEMP_AGE) {
+                remove_if_exists(&self.root.join(name)).await?;
+            }
+        }
+        let quarantine_dir = self.root.join(QUARANTINE_DIR);
+        for (name, modified) in list_files(&quarantine_dir).await? {
+            // Renaming keeps the time of the last write, so the name tells when it was quarantined.
+            let quarantined = name
+                .rsplit_once('.')
+                .and_then(|(_, x)| x.parse().ok())
+                .map_or(modified, |x| UNIX_EPOCH + Duration::from_secs(x));
+            if older(quarantined, QUARANTINE_RETENTION) {
+                remove_if_exists(&quarantine_dir.join(name)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CacheStore for FilesystemStore {
+    async fn get(&self, info_hash: &str) -> Result<Option<TorrentCache>> {
+        let cache_path = self.root.join(info_hash);
+
+        create_dir_all(&self.root).await?;
+        if !tokio::fs::try_exists(&cache_path).await? {
+            return Ok(None);
+        }
+
+        let mut buf = Vec::new();
+        tokio::fs::File::open(&cache_path)
+            .await?
+            .read_to_end(&mut buf)
+            .await?;
+        match codec::decode(&buf) {
+            Ok(cache) => Ok(Some(cache)),
+            Err(e) => {
+                warn!(info_hash, "quarantining corrupt cache entry: {e:#}");
+                self.quarantine(info_hash).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn put(&self, info_hash: &str, value: &TorrentCache) -> Result<()> {
+        let cache_path = self.root.join(info_hash);
+        let temp_path = self.temp_path(info_hash);
+
+        create_dir_all(&self.root).await?;
+
+        let buf = codec::encode(value)?;
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        let result = async {
+            file.write_all(&buf).await?;
+            file.sync_all().await
+        }
+        .await;
+        drop(file);
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+        tokio::fs::rename(&temp_path, &cache_path).await?;
+
+        // Persist the rename itself.
+        #[cfg(unix)]
+        tokio::fs::File::open(&self.root).await?.sync_all().await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, info_hash: &str) -> Result<()> {
+        remove_if_exists(&self.root.join(info_hash)).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        create_dir_all(&self.root).await?;
+
+        let mut result = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file()
+                && let Ok(name) = entry.file_name().into_string()
+                && !name.starts_with('.')
+            {
+                result.push(name);
+            }
+        }
+        Ok(result)
+    }
+
+    async fn usage(&self) -> Result<Vec<(String, u64)>> {
+        let mut result = Vec::new();
+        for info_hash in self.list().await? {
+            match tokio::fs::metadata(self.root.join(&info_hash)).await {
+                Ok(metadata) => result.push((info_hash, metadata.len())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(result)
+    }
+
+    async fn scan_expired(&self, before: SystemTime) -> Result<Vec<String>> {
+        let mut result = Vec::new();
+        for info_hash in self.list().await? {
+            if let Some(cache) = self.get(&info_hash).await?
+                && cache.expiration() < before
+            {
+                result.push(info_hash);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Nothing but the entry itself tells when its peers expire, so every entry is read. As garbage
+    /// collection calls this on every run, leftovers of writes and quarantine are removed too.
+    async fn scan_prunable(&self, before: SystemTime) -> Result<Vec<String>> {
+        self.remove_leftovers(before).await?;
+        let mut result = Vec::new();
+        for info_hash in self.list().await? {
+            if let Some(cache) = self.get(&info_hash).await?
+                && cache.next_expiry().is_some_and(|x| x < before)
+            {
+                result.push(info_hash);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{FilesystemStore, QUARANTINE_DIR, STALE_TEMP_AGE};
+    use crate::cache::{TorrentCache, store::CacheStore as _};
+
+    #[tokio::test]
+    async fn removes_leftovers() {
+        let root = std::env::temp_dir().join(format!("pt_cracker-{:016x}", rand::random::<u64>()));
+        let store = FilesystemStore::new(root.clone());
+        store.put("entry", &TorrentCache::default()).await.unwrap();
+        std::fs::write(root.join(".left.tmp"), b"").unwrap();
+        let quarantine_dir = root.join(QUARANTINE_DIR);
+        std::fs::create_dir(&quarantine_dir).unwrap();
+        let now = SystemTime::now();
+        let recent = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        std::fs::write(quarantine_dir.join("old.0"), b"").unwrap();
+        std::fs::write(quarantine_dir.join(format!("recent.{recent}")), b"").unwrap();
+
+        // Quarantining what another reader already moved away is not an error.
+        store.quarantine("missing").await.unwrap();
+
+        store.scan_prunable(now).await.unwrap();
+        assert!(root.join(".left.tmp").exists());
+        store
+            .scan_prunable(now + STALE_TEMP_AGE + Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(!root.join(".left.tmp").exists());
+        assert!(!quarantine_dir.join("old.0").exists());
+        assert!(quarantine_dir.join(format!("recent.{recent}")).exists());
+        assert_eq!(store.list().await.unwrap(), ["entry"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
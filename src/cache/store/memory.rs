@@ -0,0 +1,68 @@
+//! Volatile store, mostly useful for tests and throwaway instances.
+
+use std::{collections::HashMap, time::SystemTime};
+
+use anyhow::Result;
+use tokio::sync::RwLock;
+
+use super::CacheStore;
+use crate::cache::{TorrentCache, codec};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: RwLock<HashMap<String, TorrentCache>>,
+}
+
+impl CacheStore for MemoryStore {
+    async fn get(&self, info_hash: &str) -> Result<Option<TorrentCache>> {
+        Ok(self.entries.read().await.get(info_hash).cloned())
+    }
+
+    async fn put(&self, info_hash: &str, value: &TorrentCache) -> Result<()> {
+        self.entries
+            .write()
+            .await
+            .insert(info_hash.to_string(), value.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, info_hash: &str) -> Result<()> {
+        self.entries.write().await.remove(info_hash);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.entries.read().await.keys().cloned().collect())
+    }
+
+    async fn usage(&self) -> Result<Vec<(String, u64)>> {
+        self.entries
+            .read()
+            .await
+            .iter()
+            .map(|(info_hash, cache)| Ok((info_hash.clone(), codec::encode(cache)?.len() as u64)))
+            .collect()
+    }
+
+    async fn scan_expired(&self, before: SystemTime) -> Result<Vec<String>> {
+        Ok(self
+            .entries
+            .read()
+            .await
+            .iter()
+            .filter(|(_, cache)| cache.expiration() < before)
+            .map(|(info_hash, _)| info_hash.clone())
+            .collect())
+    }
+
+    async fn scan_prunable(&self, before: SystemTime) -> Result<Vec<String>> {
+        Ok(self
+            .entries
+            .read()
+            .await
+            .iter()
+            .filter(|(_, cache)| cache.next_expiry().is_some_and(|x| x < before))
+            .map(|(info_hash, _)| info_hash.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,276 @@
+//! Embedded SQLite database, for instances caching far more torrents than a directory handles
+//! comfortably. The overall expiration of each entry and when its first peer or client expires are
+//! kept in their own indexed columns, so that scanning for expired entries or peers does not need
+//! to decode every row. Rows that fail to decode are logged and deleted, and treated as a cache
+//! miss.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension as _, params};
+use tracing::warn;
+
+use super::CacheStore;
+use crate::cache::{TorrentCache, codec};
+
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+fn to_unix_secs(value: SystemTime) -> i64 {
+    value
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS torrents (
+                 info_hash TEXT PRIMARY KEY,
+                 expire INTEGER NOT NULL,
+                 data BLOB NOT NULL,
+                 next_expire INTEGER
+             );
+             CREATE INDEX IF NOT EXISTS torrents_expire ON torrents (expire);",
+        )?;
+        // Rows written before `next_expire` existed have it unset, and are all pruned once.
+        if connection
+            .prepare("SELECT next_expire FROM torrents LIMIT 0")
+            .is_err()
+        {
+            connection.execute_batch("ALTER TABLE torrents ADD COLUMN next_expire INTEGER;")?;
+        }
+        connection.execute_batch(
+            "CREATE INDEX IF NOT EXISTS torrents_next_expire ON torrents (next_expire);",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// `rusqlite` is blocking, so every query runs on the blocking thread pool.
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&connection.lock().unwrap())).await?
+    }
+}
+
+impl CacheStore for SqliteStore {
+    async fn get(&self, info_hash: &str) -> Result<Option<TorrentCache>> {
+        let key = info_hash.to_string();
+        let data: Option<Vec<u8>> = self
+            .with_connection(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT data FROM torrents WHERE info_hash = ?1",
+                        params![key],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        match codec::decode(&data) {
+            Ok(cache) => Ok(Some(cache)),
+            Err(e) => {
+                warn!(info_hash, "deleting corrupt cache entry: {e:#}");
+                // Unless it was overwritten in the meantime.
+                let key = info_hash.to_string();
+                self.with_connection(move |connection| {
+                    connection.execute(
+                        "DELETE FROM torrents WHERE info_hash = ?1 AND data = ?2",
+                        params![key, data],
+                    )?;
+                    Ok(())
+                })
+                .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn put(&self, info_hash: &str, value: &TorrentCache) -> Result<()> {
+        let info_hash = info_hash.to_string();
+        let expire = to_unix_secs(value.expiration());
+        // Entries without peers nor clients never need pruning.
+        let next_expire = value.next_expiry().map_or(i64::MAX, to_unix_secs);
+        let data = codec::encode(value)?;
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO torrents (info_hash, expire, data, next_expire) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (info_hash) DO UPDATE SET expire = ?2, data = ?3, next_expire = ?4",
+                params![info_hash, expire, data, next_expire],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete(&self, info_hash: &str) -> Result<()> {
+        let info_hash = info_hash.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM torrents WHERE info_hash = ?1",
+                params![info_hash],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.with_connection(|connection| {
+            Ok(connection
+                .prepare("SELECT info_hash FROM torrents")?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn usage(&self) -> Result<Vec<(String, u64)>> {
+        self.with_connection(|connection| {
+            Ok(connection
+                .prepare("SELECT info_hash, length(data) FROM torrents")?
+                .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+                .collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn scan_expired(&self, before: SystemTime) -> Result<Vec<String>> {
+        let before = to_unix_secs(before);
+        self.with_connection(move |connection| {
+            Ok(connection
+                .prepare("SELECT info_hash FROM torrents WHERE expire < ?1")?
+                .query_map(params![before], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn scan_prunable(&self, before: SystemTime) -> Result<Vec<String>> {
+        let before = to_unix_secs(before);
+        self.with_connection(move |connection| {
+            Ok(connection
+                .prepare(
+                    "SELECT info_hash FROM torrents WHERE next_expire IS NULL OR next_expire < ?1",
+                )?
+                .query_map(params![before], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    /// Moves the write-ahead log into the database, which SQLite only does by itself when the
+    /// connection is closed, and the connection is never closed as it lives until exit.
+    async fn flush(&self) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    use rusqlite::Connection;
+
+    use super::SqliteStore;
+    use crate::cache::{Peer, TorrentCache, store::CacheStore as _};
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("pt_cracker-{:016x}.sqlite3", rand::random::<u64>()))
+    }
+
+    fn remove(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn scans_prunable_entries() {
+        let path = temp_path();
+        // A database of an older version, without `next_expire`.
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE torrents (
+                     info_hash TEXT PRIMARY KEY,
+                     expire INTEGER NOT NULL,
+                     data BLOB NOT NULL
+                 );
+                 INSERT INTO torrents VALUES ('old', 0, x'');",
+            )
+            .unwrap();
+        let store = SqliteStore::open(&path).unwrap();
+
+        let now = SystemTime::now();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut cache = TorrentCache::default();
+        cache.peers_addr.insert(addr, now + Duration::from_secs(60));
+        cache.peers_time.insert(Peer {
+            expire: now + Duration::from_secs(60),
+            addr,
+        });
+        store.put("peer", &cache).await.unwrap();
+        store.put("empty", &TorrentCache::default()).await.unwrap();
+
+        let mut prunable = store.scan_prunable(now).await.unwrap();
+        prunable.sort();
+        assert_eq!(prunable, ["old"]);
+        let mut prunable = store
+            .scan_prunable(now + Duration::from_secs(120))
+            .await
+            .unwrap();
+        prunable.sort();
+        assert_eq!(prunable, ["old", "peer"]);
+
+        drop(store);
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn deletes_corrupt_entries() {
+        let path = temp_path();
+        let store = SqliteStore::open(&path).unwrap();
+        store.put("good", &TorrentCache::default()).await.unwrap();
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute_batch("INSERT INTO torrents VALUES ('bad', 0, x'505443ff', NULL);")
+            .unwrap();
+
+        assert!(store.get("bad").await.unwrap().is_none());
+        assert_eq!(store.list().await.unwrap(), ["good"]);
+        assert!(store.get("good").await.unwrap().is_some());
+
+        drop(store);
+        remove(&path);
+    }
+}
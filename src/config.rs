@@ -0,0 +1,540 @@
+//! Typed server configuration.
+//!
+//! Settings come from an optional TOML file, then from environment variables, then from command
+//! line flags, each overriding the previous. Everything is validated once at startup, so that the
+//! rest of the code can read [`config`] without checking again.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use serde_derive::Deserialize;
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+use crate::tracker::ProxyRules;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cache: CacheConfig,
+    pub origin: OriginConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// How BitTorrent clients reach this service, used when rewriting torrents.
+    pub base_url: String,
+    pub index_path: PathBuf,
+    /// The `ttl` of rewritten tracker URLs, in seconds.
+    pub default_ttl: u64,
+    /// Upper bound of the `ttl` given by clients and of the interval required by origin trackers.
+    pub max_ttl: u64,
+    pub multi_tracker: bool,
+    pub started_freshness: u64,
+    pub interval_floor: u64,
+    pub interval_ceiling: u64,
+    /// If set, `/readyz` fails unless an origin tracker responded within this many seconds.
+    pub ready_announce_age: Option<u64>,
+    /// Bearer token of the admin API, which is disabled if unset.
+    pub admin_token: Option<String>,
+    /// How long to wait for in-flight requests on shutdown, in seconds.
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: ([127, 0, 0, 1], 3000).into(),
+            base_url: "https://tracker.submy.org".to_string(),
+            index_path: "www/static/index.html".into(),
+            default_ttl: 28800,
+            max_ttl: 604800,
+            multi_tracker: false,
+            started_freshness: 1800,
+            interval_floor: 30,
+            interval_ceiling: 3600,
+            ready_announce_age: None,
+            admin_token: None,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    #[default]
+    Filesystem,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for CacheBackendKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(value, true)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// The directory to keep the cache in, defaulting to the user cache directory.
+    pub root: Option<PathBuf>,
+    pub backend: CacheBackendKind,
+    pub hot_entries: usize,
+    pub gc_interval: u64,
+    pub gc_grace: u64,
+    pub max_entries: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            root: None,
+            backend: CacheBackendKind::Filesystem,
+            hot_entries: 1024,
+            gc_interval: 600,
+            gc_grace: 86400,
+            max_entries: None,
+            max_bytes: None,
+        }
+    }
+}
+
+/// Routes trackers whose host matches `host` to `route`, see [`ProxyRules`].
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRule {
+    pub host: String,
+    pub route: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OriginConfig {
+    pub proxy: Option<String>,
+    pub proxy_rules: Vec<ProxyRule>,
+    pub timeout: u64,
+    pub keepalive: u64,
+    pub connections: usize,
+    pub host_connections: usize,
+    pub host_rate: f64,
+    pub host_burst: u32,
+    /// How long a refresh waits for the limits above when there is a stale cache to serve.
+    pub stale_wait: f64,
+    /// How long a refresh waits for the limits above when there is nothing to serve.
+    pub cold_wait: f64,
+    pub retries: u32,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: u64,
+}
+
+impl Default for OriginConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            proxy_rules: Vec::new(),
+            timeout: 20,
+            keepalive: 90,
+            connections: 10,
+            host_connections: 4,
+            host_rate: 0.,
+            host_burst: 10,
+            stale_wait: 1.,
+            cold_wait: 30.,
+            retries: 2,
+            breaker_threshold: 5,
+            breaker_cooldown: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(value, true)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Which logs to emit, as `tracing` filter directives.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// Command line flags overriding the configuration.
+#[derive(clap::Args, Default, Debug)]
+pub struct Overrides {
+    /// Path of the TOML configuration file.
+    #[arg(short, long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on.
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub bind: Option<SocketAddr>,
+    /// How BitTorrent clients reach this service.
+    #[arg(long, global = true, value_name = "URL")]
+    pub base_url: Option<String>,
+    /// Proxy for origin trackers matching no proxy rule.
+    #[arg(long, global = true, value_name = "URL")]
+    pub proxy: Option<String>,
+    /// Directory to keep the cache in.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub cache_root: Option<PathBuf>,
+    /// Where to store the cache.
+    #[arg(long, global = true, value_name = "BACKEND")]
+    pub cache_backend: Option<CacheBackendKind>,
+    /// Path of the web interface page.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub index_path: Option<PathBuf>,
+    /// Which logs to emit, e.g. `debug` or `warn,pt_cracker=debug`.
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+}
+
+/// Parses the environment variable `name`, if set.
+fn parse_env<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    let Ok(value) = std::env::var(name) else {
+        return Ok(None);
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("invalid {name} {value:?}: {e}"))
+}
+
+/// Overwrites `target` with the environment variable `name` if set.
+fn env<T: FromStr>(name: &str, target: &mut T) -> Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Some(value) = parse_env(name)? {
+        *target = value;
+    }
+    Ok(())
+}
+
+fn env_option<T: FromStr>(name: &str, target: &mut Option<T>) -> Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Some(value) = parse_env(name)? {
+        *target = Some(value);
+    }
+    Ok(())
+}
+
+/// Parses `PROXY_RULES`, i.e. comma-separated `pattern=route` pairs.
+fn parse_proxy_rules(value: &str) -> Result<Vec<ProxyRule>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|rule| {
+            let (host, route) = rule
+                .split_once('=')
+                .ok_or(anyhow::anyhow!("proxy rule {rule:?} is not pattern=route"))?;
+            Ok(ProxyRule {
+                host: host.trim().to_string(),
+                route: route.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+impl Config {
+    /// Reads the file given by `overrides`, if any, and applies the environment and `overrides`.
+    pub fn load(overrides: Overrides) -> Result<Self> {
+        let mut config = match &overrides.config {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.apply_overrides(overrides);
+        config.resolve_cache_root()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Settles the cache directory, so that a missing `HOME` is reported at startup.
+    fn resolve_cache_root(&mut self) -> Result<()> {
+        if self.cache.root.is_none() {
+            self.cache.root = Some(user_cache_dir().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "invalid configuration: cannot determine cache root: set cache.root or HOME"
+                )
+            })?);
+        }
+        Ok(())
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        let Self {
+            server,
+            cache,
+            origin,
+            log,
+        } = self;
+
+        env("BASE_URL", &mut server.base_url)?;
+        if let Ok(value) = std::env::var("MULTI_TRACKER") {
+            server.multi_tracker = value == "1" || value == "true";
+        }
+        env("MAX_TTL", &mut server.max_ttl)?;
+        env("STARTED_FRESHNESS", &mut server.started_freshness)?;
+        env("INTERVAL_FLOOR", &mut server.interval_floor)?;
+        env("INTERVAL_CEILING", &mut server.interval_ceiling)?;
+        env_option("READY_ANNOUNCE_AGE", &mut server.ready_announce_age)?;
+        env_option("ADMIN_TOKEN", &mut server.admin_token)?;
+        env("SHUTDOWN_TIMEOUT", &mut server.shutdown_timeout)?;
+
+        if let Some(value) = std::env::var_os("CACHE_ROOT") {
+            cache.root = Some(value.into());
+        }
+        env("CACHE_BACKEND", &mut cache.backend)?;
+        env("HOT_CACHE_ENTRIES", &mut cache.hot_entries)?;
+        env("GC_INTERVAL", &mut cache.gc_interval)?;
+        env("GC_GRACE", &mut cache.gc_grace)?;
+        env_option("CACHE_MAX_ENTRIES", &mut cache.max_entries)?;
+        env_option("CACHE_MAX_BYTES", &mut cache.max_bytes)?;
+
+        if let Ok(value) = std::env::var("PROXY") {
+            origin.proxy = Some(value);
+        }
+        if let Ok(value) = std::env::var("PROXY_RULES") {
+            origin.proxy_rules = parse_proxy_rules(&value).context("invalid PROXY_RULES")?;
+        }
+        env("ORIGIN_TIMEOUT", &mut origin.timeout)?;
+        env("ORIGIN_KEEPALIVE", &mut origin.keepalive)?;
+        env("ORIGIN_CONNECTIONS", &mut origin.connections)?;
+        env("ORIGIN_HOST_CONNECTIONS", &mut origin.host_connections)?;
+        env("ORIGIN_HOST_RATE", &mut origin.host_rate)?;
+        env("ORIGIN_HOST_BURST", &mut origin.host_burst)?;
+        env("ORIGIN_STALE_WAIT", &mut origin.stale_wait)?;
+        env("ORIGIN_COLD_WAIT", &mut origin.cold_wait)?;
+        env("ANNOUNCE_RETRIES", &mut origin.retries)?;
+        env("BREAKER_THRESHOLD", &mut origin.breaker_threshold)?;
+        env("BREAKER_COOLDOWN", &mut origin.breaker_cooldown)?;
+
+        env("LOG_LEVEL", &mut log.level)?;
+        env("LOG_FORMAT", &mut log.format)?;
+        Ok(())
+    }
+
+    fn apply_overrides(&mut self, overrides: Overrides) {
+        let Overrides {
+            config: _,
+            bind,
+            base_url,
+            proxy,
+            cache_root,
+            cache_backend,
+            index_path,
+            log_level,
+        } = overrides;
+        if let Some(bind) = bind {
+            self.server.bind = bind;
+        }
+        if let Some(base_url) = base_url {
+            self.server.base_url = base_url;
+        }
+        if let Some(index_path) = index_path {
+            self.server.index_path = index_path;
+        }
+        if let Some(proxy) = proxy {
+            self.origin.proxy = Some(proxy);
+        }
+        if let Some(cache_root) = cache_root {
+            self.cache.root = Some(cache_root);
+        }
+        if let Some(cache_backend) = cache_backend {
+            self.cache.backend = cache_backend;
+        }
+        if let Some(log_level) = log_level {
+            self.log.level = log_level;
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let Self {
+            server,
+            cache,
+            origin,
+            log,
+        } = self;
+        let ensure = |condition: bool, message: &str| {
+            if condition {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("invalid configuration: {message}"))
+            }
+        };
+
+        let base_url = Url::parse(&server.base_url)
+            .with_context(|| format!("invalid configuration: base_url {:?}", server.base_url))?;
+        ensure(
+            ["http", "https"].contains(&base_url.scheme()),
+            "base_url must be an http or https URL",
+        )?;
+        ensure(server.default_ttl > 0, "default_ttl must be positive")?;
+        ensure(
+            server.default_ttl <= server.max_ttl,
+            "default_ttl must not exceed max_ttl",
+        )?;
+        ensure(
+            server.interval_floor <= server.interval_ceiling,
+            "interval_floor must not exceed interval_ceiling",
+        )?;
+        ensure(
+            server.admin_token.as_ref().is_none_or(|x| x.len() >= 16),
+            "admin_token must be at least 16 characters long",
+        )?;
+
+        ensure(cache.gc_interval > 0, "gc_interval must be positive")?;
+
+        ensure(origin.timeout > 0, "origin timeout must be positive")?;
+        ensure(origin.connections > 0, "connections must be positive")?;
+        ensure(
+            origin.host_connections > 0,
+            "host_connections must be positive",
+        )?;
+        ensure(
+            origin.host_rate.is_finite() && origin.host_rate >= 0.,
+            "host_rate must not be negative",
+        )?;
+        ensure(origin.host_burst > 0, "host_burst must be positive")?;
+        ensure(
+            origin.stale_wait.is_finite() && origin.stale_wait >= 0.,
+            "stale_wait must not be negative",
+        )?;
+        ensure(
+            origin.cold_wait.is_finite() && origin.cold_wait > 0.,
+            "cold_wait must be positive",
+        )?;
+        ensure(
+            origin.breaker_threshold > 0,
+            "breaker_threshold must be positive",
+        )?;
+        ProxyRules::new(&origin.proxy_rules, origin.proxy.as_deref())
+            .context("invalid configuration")?;
+
+        EnvFilter::try_new(&log.level)
+            .with_context(|| format!("invalid configuration: log level {:?}", log.level))?;
+        Ok(())
+    }
+}
+
+/// The user cache directory, `$XDG_CACHE_HOME` or else `~/.cache`.
+pub fn user_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".cache")))
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn init_config(config: Config) -> Result<()> {
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("configuration is already initialized"))
+}
+
+/// Whether [`init_config`] was called, rather than [`config`] falling back to the defaults.
+pub fn is_config_initialized() -> bool {
+    CONFIG.get().is_some()
+}
+
+/// The configuration of this process. Falls back to the defaults if [`init_config`] was never
+/// called, e.g. in tests.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, parse_proxy_rules};
+
+    #[test]
+    fn reads_partial_files() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            bind = "0.0.0.0:8080"
+
+            [[origin.proxy_rules]]
+            host = "*.example.com"
+            route = "direct"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.bind.port(), 8080);
+        assert_eq!(config.server.default_ttl, 28800);
+        assert_eq!(config.origin.proxy_rules[0].route, "direct");
+    }
+
+    #[test]
+    fn reads_example_file() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_settings() {
+        assert!(toml::from_str::<Config>("[server]\nbnid = \"0.0.0.0:80\"").is_err());
+        assert!(toml::from_str::<Config>("[cache]\nbackend = \"redis\"").is_err());
+
+        let mut config = Config::default();
+        config.server.interval_floor = 7200;
+        let e = config.validate().unwrap_err().to_string();
+        assert!(e.contains("interval_floor"), "{e}");
+    }
+
+    #[test]
+    fn parses_proxy_rules() {
+        let rules = parse_proxy_rules("a.com=direct, *.b.com = socks5://x:1080").unwrap();
+        assert_eq!(rules[1].host, "*.b.com");
+        assert_eq!(rules[1].route, "socks5://x:1080");
+        assert!(parse_proxy_rules("a.com").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Liveness and readiness of the service, for container health checks.
+//!
+//! The service is alive as long as it answers at all. It is ready once it can actually serve
+//! announces: its configuration was loaded, the cache can be written, and requests to origin
+//! trackers can be built. Optionally, an origin tracker must have responded recently, which tells
+//! apart instances whose proxy or network is broken.
+
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    cache::check_cache_dir,
+    config::{config, is_config_initialized},
+    tracker::{check_clients, last_response},
+};
+
+static STARTED: LazyLock<SystemTime> = LazyLock::new(SystemTime::now);
+
+/// Set once clients to origin trackers were built successfully, which is not worth repeating.
+static CLIENTS_CHECKED: OnceLock<()> = OnceLock::new();
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Fail,
+}
+
+impl Status {
+    fn of(ok: bool) -> Self {
+        if ok { Self::Ok } else { Self::Fail }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub status: Status,
+    /// What was found, or why the check failed.
+    pub detail: String,
+}
+
+impl Check {
+    fn new(ok: bool, detail: impl Into<String>) -> Self {
+        Self {
+            status: Status::of(ok),
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Liveness {
+    pub status: Status,
+    pub uptime: u64,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    /// [`Status::Ok`] if all of the checks are.
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Marks the start of the service, which the uptime and the origin check count from.
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+fn since(time: SystemTime) -> Duration {
+    SystemTime::now().duration_since(time).unwrap_or_default()
+}
+
+pub fn liveness() -> Liveness {
+    Liveness {
+        status: Status::Ok,
+        uptime: since(*STARTED).as_secs(),
+    }
+}
+
+pub async fn readiness() -> Readiness {
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "config",
+        if is_config_initialized() {
+            Check::new(true, "loaded")
+        } else {
+            Check::new(false, "not loaded")
+        },
+    );
+    checks.insert(
+        "cache",
+        match check_cache_dir().await {
+            Ok(Some(dir)) => Check::new(true, format!("{} is writable", dir.display())),
+            Ok(None) => Check::new(true, "kept in memory"),
+            Err(e) => Check::new(false, format!("{e:#}")),
+        },
+    );
+    let clients = match CLIENTS_CHECKED.get() {
+        Some(()) => Ok(()),
+        None => check_clients().inspect(|()| {
+            let _ = CLIENTS_CHECKED.set(());
+        }),
+    };
+    checks.insert(
+        "http_client",
+        match clients {
+            Ok(()) => Check::new(true, "built"),
+            Err(e) => Check::new(false, format!("{e:#}")),
+        },
+    );
+    checks.insert(
+        "origin",
+        check_origin(
+            last_response().map(since),
+            since(*STARTED),
+            config().server.ready_announce_age.map(Duration::from_secs),
+        ),
+    );
+    Readiness {
+        status: Status::of(checks.values().all(|x| x.status == Status::Ok)),
+        checks,
+    }
+}
+
+/// Whether an origin tracker responded within `max_age`, if required. A service which has just
+/// started has had no chance to announce yet, so it is given `max_age` to do so.
+fn check_origin(
+    last_response: Option<Duration>,
+    uptime: Duration,
+    max_age: Option<Duration>,
+) -> Check {
+    let detail = match last_response {
+        Some(age) => format!("last response {}s ago", age.as_secs()),
+        None => "no response since startup".to_string(),
+    };
+    let ok = max_age.is_none_or(|max_age| last_response.unwrap_or(uptime) <= max_age);
+    Check::new(ok, detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Status, check_origin};
+
+    #[test]
+    fn checks_origin_age() {
+        let minutes = |x: u64| Some(Duration::from_secs(x * 60));
+        let status = |last, uptime, max_age| check_origin(last, uptime, max_age).status;
+        let uptime = Duration::from_secs(3600);
+        assert_eq!(status(None, uptime, None), Status::Ok);
+        assert_eq!(status(minutes(90), uptime, None), Status::Ok);
+        assert_eq!(status(minutes(5), uptime, minutes(10)), Status::Ok);
+        assert_eq!(status(minutes(15), uptime, minutes(10)), Status::Fail);
+        assert_eq!(status(None, uptime, minutes(10)), Status::Fail);
+        assert_eq!(
+            status(None, Duration::from_secs(60), minutes(10)),
+            Status::Ok
+        );
+    }
+}
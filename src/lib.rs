@@ -0,0 +1,18 @@
+//! PT Cracker, a peer list cache service for private trackers.
+//!
+//! The library holds everything but the web server and the command line, which live in the
+//! `pt_cracker` binary, so that other tools can parse and rewrite torrents, announce to trackers
+//! the way the service does, and read or maintain its cache.
+//!
+//! Most functions read their settings from [`config::config`]. Call [`config::init_config`] first
+//! to use anything but the defaults, and [`cache::init_cache_store`] before touching the cache.
+
+pub mod bytes_bencode;
+pub mod cache;
+pub mod config;
+pub mod health;
+pub mod logging;
+pub mod metainfo;
+pub mod metrics;
+pub mod tracker;
+mod utils;
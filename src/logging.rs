@@ -0,0 +1,334 @@
+//! Structured logs, with secrets masked.
+//!
+//! Logs are emitted through `tracing`, within a span per web request, so that everything logged
+//! while serving an announce carries its request ID. Tracker URLs carry passkeys, and some of them
+//! inevitably end up in a message or an error, so every line is passed through [`redact`] right
+//! before being written, whatever logged it.
+//!
+//! The latest warnings and errors of this crate are also kept in memory, see [`recent_errors`].
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fmt::{Debug, Write as _},
+    io::{IsTerminal as _, Write},
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    EnvFilter,
+    layer::{Context, Layer, SubscriberExt as _},
+    util::SubscriberInitExt as _,
+};
+
+use crate::{
+    config::{LogFormat, config},
+    tracker::is_secret_segment,
+};
+
+/// Query parameters whose values are masked.
+const SECRET_PARAMS: [&str; 11] = [
+    "passkey",
+    "authkey",
+    "auth",
+    "token",
+    "secret",
+    "apikey",
+    "api_key",
+    "key",
+    "pid",
+    "torrent_pass",
+    "sign",
+];
+
+const MASK: &str = "***";
+
+/// Number of warnings and errors kept by [`RecentErrors`].
+const RECENT_ERRORS_LEN: usize = 100;
+
+static RECENT_ERRORS: Mutex<VecDeque<LoggedError>> = Mutex::new(VecDeque::new());
+
+fn starts_with_ignore_case(text: &[u8], prefix: &str) -> bool {
+    text.get(..prefix.len())
+        .is_some_and(|x| x.eq_ignore_ascii_case(prefix.as_bytes()))
+}
+
+fn ends_with_ignore_case(text: &[u8], suffix: &str) -> bool {
+    text.len() >= suffix.len()
+        && text[text.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+}
+
+/// Whether a URL component ends at `text`, e.g. at the end of a quoted URL in a message.
+fn is_terminator(text: &[u8]) -> bool {
+    match text.first() {
+        None => true,
+        Some(x) => {
+            x.is_ascii_whitespace()
+                || b"&#\"'`,;)]}>\\".contains(x)
+                || starts_with_ignore_case(text, "%26")
+        }
+    }
+}
+
+/// The value of a secret query parameter named at `start`.
+fn secret_param(text: &[u8], start: usize) -> Option<(usize, usize)> {
+    let name_len = text[start..]
+        .iter()
+        .take_while(|x| x.is_ascii_alphanumeric() || **x == b'_')
+        .count();
+    let name = &text[start..start + name_len];
+    if !SECRET_PARAMS
+        .iter()
+        .any(|x| x.as_bytes().eq_ignore_ascii_case(name))
+    {
+        return None;
+    }
+    let rest = &text[start + name_len..];
+    let value_start = start
+        + name_len
+        + if rest.first() == Some(&b'=') {
+            1
+        } else if starts_with_ignore_case(rest, "%3D") {
+            3
+        } else {
+            return None;
+        };
+    let value_len = (value_start..text.len())
+        .find(|&i| is_terminator(&text[i..]))
+        .unwrap_or(text.len())
+        - value_start;
+    (value_len > 0).then_some((value_start, value_start + value_len))
+}
+
+/// A path segment at `start` that may be a passkey, as [`is_secret_segment`] tells.
+fn secret_segment(text: &[u8], start: usize) -> Option<(usize, usize)> {
+    let len = (start..text.len())
+        .find(|&i| {
+            let rest = &text[i..];
+            is_terminator(rest)
+                || b"/?".contains(&rest[0])
+                || starts_with_ignore_case(rest, "%2F")
+                || starts_with_ignore_case(rest, "%3F")
+        })
+        .unwrap_or(text.len())
+        - start;
+    is_secret_segment(&text[start..start + len]).then_some((start, start + len))
+}
+
+/// Masks passkeys and other secrets in URLs and query strings found anywhere in `text`, also if
+/// percent-encoded once, e.g. within the `tracker_url` of our own announce URLs. Secrets are the
+/// values of query parameters such as `passkey` or `token`, and path segments that may be
+/// passkeys, as in [`crate::tracker::tracker_key`]. Hosts, which follow `//`, are kept.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let bytes = text.as_bytes();
+    let mut redacted = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let secret = if rest[0] == b'?' || rest[0] == b'&' {
+            secret_param(bytes, i + 1)
+        } else if starts_with_ignore_case(rest, "%3F") || starts_with_ignore_case(rest, "%26") {
+            secret_param(bytes, i + 3)
+        } else if rest[0] == b'/' && !bytes[..i].ends_with(b"/") {
+            secret_segment(bytes, i + 1)
+        } else if starts_with_ignore_case(rest, "%2F") && !ends_with_ignore_case(&bytes[..i], "%2F")
+        {
+            secret_segment(bytes, i + 3)
+        } else {
+            None
+        };
+        match secret {
+            // Both ends are next to ASCII characters, so they are on character boundaries.
+            Some((start, end)) => {
+                redacted.push_str(&text[copied..start]);
+                redacted.push_str(MASK);
+                copied = end;
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    redacted.push_str(&text[copied..]);
+    Cow::Owned(redacted)
+}
+
+/// Redacts everything written through it. Log lines are formatted completely before being written
+/// at once, so that a secret is never split across writes.
+struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .write_all(redact(&String::from_utf8_lossy(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A warning or error logged by this crate, with secrets redacted.
+#[derive(Clone)]
+pub struct LoggedError {
+    pub time: SystemTime,
+    pub level: Level,
+    /// The message followed by the other fields of the event, as `name=value`.
+    pub message: String,
+}
+
+/// Formats the fields of an event like the text format does.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Keeps the latest warnings and errors of this crate. Those of dependencies are left out, e.g.
+/// warp warns about every client error.
+struct RecentErrors;
+
+impl<S: Subscriber> Layer<S> for RecentErrors {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN || !metadata.target().starts_with("pt_cracker") {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let error = LoggedError {
+            time: SystemTime::now(),
+            level: *metadata.level(),
+            message: redact(&(visitor.message + &visitor.fields)).into_owned(),
+        };
+        let mut errors = RECENT_ERRORS.lock().unwrap_or_else(PoisonError::into_inner);
+        if errors.len() == RECENT_ERRORS_LEN {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+}
+
+/// The latest warnings and errors logged by this crate, oldest first.
+pub fn recent_errors() -> Vec<LoggedError> {
+    RECENT_ERRORS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Logs to stderr as configured by [`config`], with secrets redacted.
+pub fn init_logging() -> Result<()> {
+    let log = &config().log;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&log.level)?)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(|| RedactingWriter(std::io::stderr()));
+    match log.format {
+        LogFormat::Text => subscriber.finish().with(RecentErrors).try_init(),
+        LogFormat::Json => subscriber.json().finish().with(RecentErrors).try_init(),
+    }
+    .map_err(|e| anyhow::anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{Level, info, warn};
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use super::{RecentErrors, recent_errors, redact};
+
+    #[test]
+    fn redacts_query_secrets() {
+        assert_eq!(
+            redact("GET https://t.example.com/announce?passkey=0123&info_hash=%12 failed"),
+            "GET https://t.example.com/announce?passkey=***&info_hash=%12 failed"
+        );
+        assert_eq!(
+            redact(r#"{"url":"http://a/b?x=1&Token=abc&authkey=def"}"#),
+            r#"{"url":"http://a/b?x=1&Token=***&authkey=***"}"#
+        );
+        assert_eq!(
+            redact("/announce?tracker_url=https%3A%2F%2Fa%2Fannounce%3Fpasskey%3D0123&ttl=60"),
+            "/announce?tracker_url=https%3A%2F%2Fa%2Fannounce%3Fpasskey%3D***&ttl=60"
+        );
+    }
+
+    #[test]
+    fn redacts_path_secrets() {
+        assert_eq!(
+            redact("https://a.example.com/0123456789abcdef0123/announce"),
+            "https://a.example.com/***/announce"
+        );
+        assert_eq!(
+            redact("https%3A%2F%2Fa%2Fannounce%2F0123456789abcdef0123 timed out"),
+            "https%3A%2F%2Fa%2Fannounce%2F*** timed out"
+        );
+        assert_eq!(
+            redact("https://t.example.com:8443/123e4567-e89b-12d3-a456-426614174000/announce.php"),
+            "https://t.example.com:8443/***/announce.php"
+        );
+        assert_eq!(
+            redact("(url: https%3A%2F%2Ft1.example.com%2Ftr%2Fa1b2c3d4)"),
+            "(url: https%3A%2F%2Ft1.example.com%2Ftr%2F***)"
+        );
+    }
+
+    #[test]
+    fn keeps_recent_errors() {
+        let subscriber = tracing_subscriber::registry().with(RecentErrors);
+        tracing::subscriber::with_default(subscriber, || {
+            info!("not an error");
+            warn!(tracker = %"https://a/announce?passkey=0123", "announce failed: timed out");
+        });
+        let errors = recent_errors();
+        let error = errors.last().unwrap();
+        assert_eq!(error.level, Level::WARN);
+        assert_eq!(
+            error.message,
+            "announce failed: timed out tracker=https://a/announce?passkey=***"
+        );
+        assert!(errors.iter().all(|x| x.message != "not an error"));
+    }
+
+    #[test]
+    fn keeps_everything_else() {
+        for text in [
+            "https://tracker.example.com:443/announce",
+            "tracker_key=https://a/b monkey=1 ?keys=2 info_hash=0123456789abcdef0123",
+            "/www.averylongdomainname.com/ ünïcödé ?passkey=",
+        ] {
+            assert_eq!(redact(text), text);
+        }
+    }
+}
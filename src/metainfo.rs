@@ -0,0 +1,330 @@
+//! Parsing and rewriting of torrent files.
+
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+use sha1::Digest as _;
+use url::Url;
+
+use crate::{bytes_bencode::BencodeObject, config::config};
+
+/// Whether `/transform` should point torrents to a single URL which carries all of their
+/// trackers, so that each refresh announces to every tier instead of one tracker per request.
+fn multi_tracker_enabled() -> bool {
+    config().server.multi_tracker
+}
+
+#[inline]
+fn process_tracker_url(value: &str, tiers: Option<&[Vec<String>]>) -> Result<String> {
+    let server = &config().server;
+
+    let mut url = Url::parse(&server.base_url)?.join("announce")?;
+    url.query_pairs_mut()
+        .append_pair("tracker_url", value)
+        .append_pair("ttl", &server.default_ttl.to_string());
+    if let Some(tiers) = tiers {
+        url.query_pairs_mut()
+            .append_pair("tiers", &serde_json::to_string(tiers)?);
+    }
+    Ok(url.to_string())
+}
+
+/// Collects the trackers of a torrent, as parsed by [`BencodeObject::try_from`], grouped into BEP
+/// 12 tiers. `announce` is only used if there is no `announce-list`.
+pub fn get_tracker_tiers(torrent: &BencodeObject) -> Result<Vec<Vec<String>>> {
+    let mut announce = None;
+    let mut tiers = Vec::new();
+
+    if let BencodeObject::List(obj) = torrent
+        && let Some(BencodeObject::Dictionary(obj)) = obj.first()
+    {
+        for (k, v) in obj {
+            let BencodeObject::Bytes(_, Some(key)) = k else {
+                continue;
+            };
+            if key == ANNOUNCE_LITERAL
+                && let BencodeObject::Bytes(_, Some(url)) = v
+            {
+                announce = Some(String::from_utf8(url.clone())?);
+            } else if key == ANNOUNCE_LIST_LITERAL
+                && let BencodeObject::List(obj) = v
+            {
+                for obj in obj {
+                    if let BencodeObject::List(obj) = obj {
+                        let mut tier = Vec::new();
+                        for obj in obj {
+                            if let BencodeObject::Bytes(_, Some(url)) = obj {
+                                tier.push(String::from_utf8(url.clone())?);
+                            }
+                        }
+                        if !tier.is_empty() {
+                            tiers.push(tier);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if tiers.is_empty()
+        && let Some(announce) = announce
+    {
+        tiers.push(vec![announce]);
+    }
+    Ok(tiers)
+}
+
+const ANNOUNCE_LITERAL: &[u8] = "announce".as_bytes();
+const ANNOUNCE_LIST_LITERAL: &[u8] = "announce-list".as_bytes();
+
+/// Points every tracker of the torrent to this service, and returns the original tracker URLs.
+/// With `server.multi_tracker` set, the torrent is left with a single tracker instead, whose URL
+/// carries all of the original tiers.
+pub fn replace_trackers_in_torrent(torrent: &mut BencodeObject) -> Result<Box<[Box<[u8]>]>> {
+    let tiers = get_tracker_tiers(torrent)?;
+    let combined_url = match tiers.first().and_then(|x| x.first()) {
+        Some(primary) if multi_tracker_enabled() => {
+            Some(process_tracker_url(primary, Some(&tiers))?.into_bytes())
+        }
+        _ => None,
+    };
+
+    let mut result = Vec::new();
+
+    let obj = match torrent {
+        BencodeObject::List(obj) => obj.first_mut(),
+        _ => None,
+    };
+    let Some(BencodeObject::Dictionary(obj)) = obj else {
+        return Err(anyhow::anyhow!("the torrent is not a dictionary"));
+    };
+    for (k, v) in obj {
+        if let BencodeObject::Bytes(len, obj) = k
+            && *len != 0
+        {
+            if obj.as_ref().unwrap() == ANNOUNCE_LITERAL
+                && let BencodeObject::Bytes(len, obj) = v
+                && *len != 0
+            {
+                let obj = obj.as_mut().unwrap();
+                let old_url = String::from_utf8(std::mem::take(obj))?;
+                let new_url = match &combined_url {
+                    Some(combined_url) => combined_url.clone(),
+                    None => process_tracker_url(&old_url, None)?.into_bytes(),
+                };
+                *len = new_url.len();
+                *obj = new_url;
+                result.push(old_url.into_bytes().into_boxed_slice());
+            } else if obj.as_ref().unwrap() == ANNOUNCE_LIST_LITERAL
+                && let BencodeObject::List(obj) = v
+            {
+                if let Some(combined_url) = &combined_url {
+                    result.extend(tiers.iter().flatten().map(|x| x.as_bytes().into()));
+                    *obj = vec![BencodeObject::List(vec![BencodeObject::Bytes(
+                        combined_url.len(),
+                        Some(combined_url.clone()),
+                    )])];
+                    continue;
+                }
+                for obj in obj {
+                    if let BencodeObject::List(obj) = obj {
+                        for obj in obj {
+                            if let BencodeObject::Bytes(len, obj) = obj
+                                && *len != 0
+                            {
+                                let obj = obj.as_mut().unwrap();
+                                let old_url = String::from_utf8(std::mem::take(obj))?;
+                                let new_url = process_tracker_url(&old_url, None)?.into_bytes();
+                                *len = new_url.len();
+                                *obj = new_url;
+                                result.push(old_url.into_bytes().into_boxed_slice());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // no need to sort, since "announce" and "announce-list" are adjacent to each other
+    result.dedup();
+    Ok(result.into_boxed_slice())
+}
+
+/// Rewrites the trackers of a torrent file as [`replace_trackers_in_torrent`] does.
+pub fn transform_torrent(torrent: &[u8]) -> Result<Vec<u8>> {
+    let mut torrent = BencodeObject::try_from(torrent)?;
+    if let BencodeObject::List(obj) = &torrent
+        && obj.is_empty()
+    {
+        return Err(anyhow::anyhow!("the torrent is empty"));
+    }
+    replace_trackers_in_torrent(&mut torrent)?;
+    let BencodeObject::List(obj) = torrent else {
+        unreachable!();
+    };
+    Ok(obj.into_iter().flat_map(Vec::<u8>::from).collect())
+}
+
+/// Formats an info hash the way most BitTorrent clients show it.
+pub fn to_hex(value: &[u8]) -> String {
+    value.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Parses an `info_hash` given either as 40 hex digits or percent-encoded.
+pub fn parse_info_hash(value: &str) -> Result<Vec<u8>> {
+    let info_hash: Vec<u8> = if value.len() == 40 && value.chars().all(|x| x.is_ascii_hexdigit()) {
+        (0..40)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+            .collect::<Result<_, _>>()?
+    } else {
+        percent_decode_str(value).collect()
+    };
+    if info_hash.len() != 20 {
+        return Err(anyhow::anyhow!(
+            "info hash {value:?} is neither 40 hex digits nor 20 percent-encoded bytes"
+        ));
+    }
+    Ok(info_hash)
+}
+
+fn field<'a>(dict: &'a [(BencodeObject, BencodeObject)], key: &str) -> Option<&'a BencodeObject> {
+    dict.iter().find_map(|(k, v)| match k {
+        BencodeObject::Bytes(_, Some(k)) if k == key.as_bytes() => Some(v),
+        _ => None,
+    })
+}
+
+fn integer(value: Option<&BencodeObject>) -> Option<u64> {
+    match value {
+        Some(BencodeObject::Integer(x)) => x.parse().ok(),
+        _ => None,
+    }
+}
+
+/// What a torrent file describes, as far as this service is concerned.
+#[derive(Debug)]
+pub struct Metainfo {
+    pub name: Option<String>,
+    /// Total size in bytes, if every file has one.
+    pub size: Option<u64>,
+    pub file_count: usize,
+    pub piece_length: Option<u64>,
+    pub piece_count: Option<usize>,
+    pub private: bool,
+    /// SHA-1 of the info dictionary, which identifies the torrent when announcing.
+    pub info_hash: [u8; 20],
+    /// SHA-256 of the info dictionary, for BitTorrent v2 torrents only.
+    pub info_hash_v2: Option<[u8; 32]>,
+    pub tiers: Vec<Vec<String>>,
+}
+
+impl Metainfo {
+    pub fn parse(torrent: &[u8]) -> Result<Self> {
+        let torrent = BencodeObject::try_from(torrent)?;
+        let tiers = get_tracker_tiers(&torrent)?;
+        let BencodeObject::List(mut root) = torrent else {
+            unreachable!();
+        };
+        let Some(BencodeObject::Dictionary(root)) = root.pop() else {
+            return Err(anyhow::anyhow!("not a torrent file"));
+        };
+        let info = root
+            .into_iter()
+            .find_map(|(k, v)| match k {
+                BencodeObject::Bytes(_, Some(k)) if k == b"info" => Some(v),
+                _ => None,
+            })
+            .ok_or(anyhow::anyhow!("the torrent has no info dictionary"))?;
+        let BencodeObject::Dictionary(fields) = &info else {
+            return Err(anyhow::anyhow!(
+                "the info of the torrent is not a dictionary"
+            ));
+        };
+
+        let name = match field(fields, "name") {
+            Some(BencodeObject::Bytes(_, Some(name))) => {
+                Some(String::from_utf8_lossy(name).into_owned())
+            }
+            _ => None,
+        };
+        let files: Vec<_> = match field(fields, "files") {
+            Some(BencodeObject::List(files)) => files
+                .iter()
+                .map(|file| match file {
+                    BencodeObject::Dictionary(file) => integer(field(file, "length")),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![integer(field(fields, "length"))],
+        };
+        let piece_count = match field(fields, "pieces") {
+            Some(BencodeObject::Bytes(len, _)) => Some(len / 20),
+            _ => None,
+        };
+        let piece_length = integer(field(fields, "piece length"));
+        let private = integer(field(fields, "private")) == Some(1);
+        let is_v2 = integer(field(fields, "meta version")) == Some(2);
+
+        let info = Vec::<u8>::from(info);
+        Ok(Self {
+            name,
+            size: files.iter().copied().sum(),
+            file_count: files.len(),
+            piece_length,
+            piece_count,
+            private,
+            info_hash: sha1::Sha1::digest(&info).into(),
+            info_hash_v2: is_v2.then(|| sha2::Sha256::digest(&info).into()),
+            tiers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metainfo, parse_info_hash, to_hex, transform_torrent};
+
+    const TORRENT: &[u8] = b"d8:announce20:http://t.example/ann4:infod6:lengthi12345e4:name4:demo\
+        12:piece lengthi16384e6:pieces20:xxxxxxxxxxxxxxxxxxxx7:privatei1eee";
+
+    #[test]
+    fn parses_metainfo() {
+        let metainfo = Metainfo::parse(TORRENT).unwrap();
+        assert_eq!(metainfo.name.as_deref(), Some("demo"));
+        assert_eq!(metainfo.size, Some(12345));
+        assert_eq!(metainfo.file_count, 1);
+        assert_eq!(metainfo.piece_length, Some(16384));
+        assert_eq!(metainfo.piece_count, Some(1));
+        assert!(metainfo.private);
+        assert_eq!(
+            to_hex(&metainfo.info_hash),
+            "73f32593cd8e8e1b190b3c50263fff8df6033dfa"
+        );
+        assert_eq!(metainfo.info_hash_v2, None);
+        assert_eq!(metainfo.tiers, [["http://t.example/ann"]]);
+        assert!(Metainfo::parse(b"d8:announce3:abce").is_err());
+    }
+
+    #[test]
+    fn rejects_torrents_without_dictionary() {
+        let error = |x: &[u8]| transform_torrent(x).unwrap_err().to_string();
+        assert_eq!(error(b""), "the torrent is empty");
+        assert_eq!(error(b"i42e"), "the torrent is not a dictionary");
+        assert_eq!(error(b"l8:announcee"), "the torrent is not a dictionary");
+    }
+
+    #[test]
+    fn parses_info_hashes() {
+        let info_hash = parse_info_hash("73F32593cd8e8e1b190b3c50263fff8df6033dfa").unwrap();
+        assert_eq!(
+            to_hex(&info_hash),
+            "73f32593cd8e8e1b190b3c50263fff8df6033dfa"
+        );
+        assert_eq!(
+            parse_info_hash("s%F3%25%93%CD%8E%8E%1B%19%0B%3CP%26%3F%FF%8D%F6%03%3D%FA").unwrap(),
+            info_hash
+        );
+        assert!(parse_info_hash("73f32593").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Prometheus metrics of the service.
+//!
+//! Every metric lives in a single registry of this process, and is recorded where it happens:
+//! requests by the web server, cache lookups and origin announces by [`crate::cache`]. The number
+//! and size of cache entries are only known after scanning the store, so they are updated by the
+//! garbage collector and are as old as its last sweep.
+
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+    core::Collector,
+};
+
+/// Buckets in seconds for waiting on origin trackers, which time out after 20 seconds by default.
+const ORIGIN_BUCKETS: [f64; 11] = [0.01, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 20., 30.];
+
+/// Most hosts of origin trackers with series of their own.
+const MAX_ORIGIN_HOSTS: usize = 1024;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Hosts of origin trackers labeled as such in [`Metrics::origin_announces`].
+    origin_hosts: Mutex<HashSet<String>>,
+    /// Requests served, by route and status code.
+    pub requests: IntCounterVec,
+    /// Time to serve a request, by route.
+    pub request_duration: HistogramVec,
+    /// Cache lookups by whether they were served from a fresh cache (`hit`), needed a refresh
+    /// (`miss`), or were served an expired cache because the origin was down or busy (`stale`).
+    pub cache_lookups: IntCounterVec,
+    /// Announces to origin trackers by host and outcome, which is `success`, `failure`, or
+    /// `circuit_open` and `throttled` for those not sent at all. Recorded through
+    /// [`Metrics::record_origin_announce`].
+    pub origin_announces: IntCounterVec,
+    /// Time for an origin tracker to respond.
+    pub origin_announce_duration: Histogram,
+    /// Time waiting for the limits on origin announces.
+    pub origin_wait: Histogram,
+    /// Time waiting for the lock of a cache entry, by `read` or `write`.
+    pub lock_wait: HistogramVec,
+    /// Number of cache entries.
+    pub cache_entries: IntGauge,
+    /// Total size of cache entries in bytes.
+    pub cache_bytes: IntGauge,
+}
+
+/// Registers a metric which is only created here, so that registering never fails.
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric is registered twice");
+    metric
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("pt_cracker".to_string()), None).unwrap();
+        let histogram = |name: &str, help: &str, buckets: &[f64]| {
+            Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets.to_vec())).unwrap()
+        };
+        Self {
+            requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("requests_total", "Requests served."),
+                    &["route", "status"],
+                )
+                .unwrap(),
+            ),
+            request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("request_duration_seconds", "Time to serve a request."),
+                    &["route"],
+                )
+                .unwrap(),
+            ),
+            cache_lookups: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("cache_lookups_total", "Cache lookups by result."),
+                    &["result"],
+                )
+                .unwrap(),
+            ),
+            origin_announces: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("origin_announces_total", "Announces to origin trackers."),
+                    &["host", "outcome"],
+                )
+                .unwrap(),
+            ),
+            origin_announce_duration: register(
+                &registry,
+                histogram(
+                    "origin_announce_duration_seconds",
+                    "Time for an origin tracker to respond.",
+                    &ORIGIN_BUCKETS,
+                ),
+            ),
+            origin_wait: register(
+                &registry,
+                histogram(
+                    "origin_wait_seconds",
+                    "Time waiting for the limits on origin announces.",
+                    &ORIGIN_BUCKETS,
+                ),
+            ),
+            lock_wait: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "cache_lock_wait_seconds",
+                        "Time waiting for the lock of a cache entry.",
+                    )
+                    .buckets(vec![0.0001, 0.001, 0.01, 0.1, 1., 10.]),
+                    &["mode"],
+                )
+                .unwrap(),
+            ),
+            cache_entries: register(
+                &registry,
+                IntGauge::new("cache_entries", "Number of cache entries.").unwrap(),
+            ),
+            cache_bytes: register(
+                &registry,
+                IntGauge::new("cache_bytes", "Total size of cache entries in bytes.").unwrap(),
+            ),
+            registry,
+            origin_hosts: Default::default(),
+        }
+    }
+
+    /// Counts an announce to an origin tracker. Tracker URLs come from clients, so a host is only
+    /// labeled as such once it responded successfully, and up to [`MAX_ORIGIN_HOSTS`] of them.
+    /// Everything else is counted under `other`, so that nobody can make up new series.
+    pub fn record_origin_announce(&self, host: &str, outcome: &str) {
+        let mut origin_hosts = self.origin_hosts.lock().unwrap();
+        let labeled = origin_hosts.contains(host)
+            || outcome == "success"
+                && origin_hosts.len() < MAX_ORIGIN_HOSTS
+                && origin_hosts.insert(host.to_string());
+        drop(origin_hosts);
+        self.origin_announces
+            .with_label_values(&[if labeled { host } else { "other" }, outcome])
+            .inc();
+    }
+
+    /// Everything recorded so far, in the Prometheus text format.
+    pub fn render(&self) -> Result<String> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+/// Every series of a counter, as its label values ordered by label name, and its count.
+pub fn counter_values(counter: &IntCounterVec) -> Vec<(Vec<String>, u64)> {
+    counter
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|x| x.value().to_string())
+                .collect();
+            (labels, metric.get_counter().get_value() as u64)
+        })
+        .collect()
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, counter_values};
+
+    #[test]
+    fn renders_metrics() {
+        let metrics = Metrics::new();
+        metrics.cache_lookups.with_label_values(&["hit"]).inc_by(3);
+        metrics.origin_wait.observe(0.07);
+        metrics.cache_entries.set(42);
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"pt_cracker_cache_lookups_total{result="hit"} 3"#));
+        assert!(text.contains(r#"pt_cracker_origin_wait_seconds_bucket{le="0.1"} 1"#));
+        assert!(text.contains(r#"pt_cracker_origin_wait_seconds_bucket{le="0.05"} 0"#));
+        assert!(text.contains("pt_cracker_cache_entries 42"));
+
+        metrics.record_origin_announce("a.example.com", "success");
+        assert_eq!(
+            counter_values(&metrics.origin_announces),
+            [(vec!["a.example.com".to_string(), "success".to_string()], 1)]
+        );
+    }
+
+    #[test]
+    fn bounds_origin_hosts() {
+        let metrics = Metrics::new();
+        metrics.record_origin_announce("a.example.com", "failure");
+        metrics.record_origin_announce("a.example.com", "success");
+        metrics.record_origin_announce("a.example.com", "failure");
+        metrics.record_origin_announce("b.example.com", "throttled");
+        let mut values = counter_values(&metrics.origin_announces);
+        values.sort();
+        let series =
+            |host: &str, outcome: &str, count| (vec![host.to_string(), outcome.to_string()], count);
+        assert_eq!(
+            values,
+            [
+                series("a.example.com", "failure", 1),
+                series("a.example.com", "success", 1),
+                series("other", "failure", 1),
+                series("other", "throttled", 1),
+            ]
+        );
+    }
+}
@@ -1,39 +1,116 @@
 //! Tracker-related data structures and helper functions.
 
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Result;
 use bt_bencode::ByteString;
-use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
-use reqwest::{Client, Method, Proxy};
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, percent_encode};
+use reqwest::Method;
 use serde_derive::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
+use tracing::{debug, trace};
 use url::Url;
 
 use crate::{
     cache::TorrentCache,
-    utils::{as_array_ref, random_client_ua, random_key, random_peer_id, random_port},
+    config::config,
+    utils::{as_array_ref, random_key, random_peer_id, random_port},
+};
+
+mod breaker;
+mod client;
+mod limits;
+mod proxy;
+
+use self::client::{ClientSettings, get_client, get_request_timeout};
+pub use self::{
+    breaker::{CircuitBreakers, CircuitOpen, CircuitState},
+    client::check_clients,
+    limits::{OriginLimits, Throttled},
+    proxy::ProxyRules,
 };
 
 #[skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct AnnounceResponse {
+pub struct AnnounceResponse {
     #[serde(rename = "failure reason")]
-    pub(crate) failure_reason: Option<String>,
+    pub failure_reason: Option<String>,
     #[serde(rename = "warning message")]
-    pub(crate) warning_message: Option<String>,
-    pub(crate) interval: Option<u64>,
+    pub warning_message: Option<String>,
+    pub interval: Option<u64>,
     #[serde(rename = "min interval")]
-    pub(crate) min_interval: Option<u64>,
+    pub min_interval: Option<u64>,
     #[serde(rename = "tracker id")]
-    pub(crate) tracker_id: Option<String>,
+    pub tracker_id: Option<String>,
     #[serde(rename = "complete")]
-    pub(crate) seeders: Option<u64>,
+    pub seeders: Option<u64>,
     #[serde(rename = "incomplete")]
-    pub(crate) leechers: Option<u64>,
-    pub(crate) peers: Option<ByteString>,
+    pub leechers: Option<u64>,
+    pub peers: Option<ByteString>,
     /// Peers with IPv6 addresses.
-    pub(crate) peers6: Option<ByteString>,
+    pub peers6: Option<ByteString>,
+}
+
+impl AnnounceResponse {
+    /// A response carrying nothing but an error message for the client to display.
+    pub fn failure(reason: String) -> Self {
+        Self {
+            failure_reason: Some(reason),
+            warning_message: None,
+            interval: None,
+            min_interval: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            peers: None,
+            peers6: None,
+        }
+    }
+
+    /// Whether the origin tracker says it does not know the torrent, e.g. because it was deleted.
+    /// There is no standard for this, so the failure reason is matched against common wordings.
+    /// It has to name the torrent: "passkey not found" or "user does not exist" are about the
+    /// client, and must not make us forget the peers cached for everyone else.
+    pub fn reports_torrent_gone(&self) -> bool {
+        const PHRASES: [&str; 5] = [
+            "not registered",
+            "unregistered",
+            "not found",
+            "not exist",
+            "deleted",
+        ];
+        const CREDENTIALS: [&str; 6] = [
+            "passkey",
+            "authkey",
+            "torrent_pass",
+            "user",
+            "client",
+            "account",
+        ];
+        self.failure_reason.as_ref().is_some_and(|reason| {
+            let reason = reason.to_lowercase();
+            reason.contains("torrent")
+                && (reason.contains("unknown")
+                    || PHRASES.iter().any(|phrase| reason.contains(phrase)))
+                && !CREDENTIALS.iter().any(|word| reason.contains(word))
+        })
+    }
+
+    /// Fails with the failure reason, unless it says the torrent is gone, which is a final answer
+    /// to be merged like peers. Other reasons, such as being rate limited, are worth retrying.
+    pub fn accepted(self) -> Result<Self> {
+        match &self.failure_reason {
+            Some(reason) if !self.reports_torrent_gone() => Err(anyhow::anyhow!(
+                "origin tracker refused the announce: {reason}"
+            )),
+            _ => Ok(self),
+        }
+    }
 }
 
 impl From<TorrentCache> for AnnounceResponse {
@@ -48,11 +125,12 @@ impl From<TorrentCache> for AnnounceResponse {
             }
         }
 
+        let (interval_floor, _) = get_interval_bounds();
         Self {
             failure_reason: None,
             warning_message: None,
-            interval: Some(30),
-            min_interval: Some(30),
+            interval: Some(interval_floor),
+            min_interval: Some(interval_floor),
             tracker_id: None,
             seeders: Some(0),
             leechers: Some(value.peers_addr.len() as u64),
@@ -62,7 +140,99 @@ impl From<TorrentCache> for AnnounceResponse {
     }
 }
 
-pub(crate) fn deserialize_peers_binary(value: &[u8]) -> Vec<SocketAddr> {
+/// Statistics of a torrent in a scrape response, following BEP 48.
+#[derive(Debug, Serialize)]
+pub struct ScrapeFile {
+    pub complete: u64,
+    pub downloaded: u64,
+    pub incomplete: u64,
+}
+
+/// A scrape response. Torrents which are not cached are left out.
+#[derive(Debug, Default, Serialize)]
+pub struct ScrapeResponse {
+    pub files: BTreeMap<ByteString, ScrapeFile>,
+}
+
+impl From<&TorrentCache> for ScrapeFile {
+    fn from(value: &TorrentCache) -> Self {
+        // Like announce responses, every cached peer is reported as a leecher.
+        Self {
+            complete: 0,
+            downloaded: value.downloaded,
+            incomplete: value.peers_addr.len() as u64,
+        }
+    }
+}
+
+/// Extracts a parameter from a raw query string as bytes. Parameters like `info_hash` and
+/// `peer_id` are percent-encoded raw bytes, which are mangled by UTF-8 based query parsers.
+pub fn get_raw_query_param(query: &str, name: &str) -> Option<Box<[u8]>> {
+    get_raw_query_params(query, name).next()
+}
+
+/// Like [`get_raw_query_param`], but yields every occurrence of a repeated parameter.
+pub fn get_raw_query_params<'a>(
+    query: &'a str,
+    name: &'a str,
+) -> impl Iterator<Item = Box<[u8]>> + 'a {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(move |&(key, _)| key == name)
+        .map(|(_, value)| percent_decode_str(value).collect())
+}
+
+/// Lower and upper bounds in seconds of the announce interval returned to clients.
+fn get_interval_bounds() -> (u64, u64) {
+    let server = &config().server;
+    (server.interval_floor, server.interval_ceiling)
+}
+
+/// Tell clients to come back no earlier than the cache could possibly change. `until_expiry` is
+/// the time left before the cache needs a refresh, which is clamped into the configured bounds.
+pub fn negotiate_interval(until_expiry: Duration) -> u64 {
+    let (floor, ceiling) = get_interval_bounds();
+    until_expiry.as_secs().clamp(floor, ceiling)
+}
+
+/// Whether a path segment of a tracker URL may be a passkey. Passkeys come in all shapes, such as
+/// hex digests, UUIDs and shorter tokens, so anything of 8 or more characters is one if it contains
+/// a digit, or if it is 16 or more characters long without a dot. Only names like `announce.php`
+/// are left.
+pub fn is_secret_segment(segment: &[u8]) -> bool {
+    segment.len() >= 8
+        && (segment.iter().any(u8::is_ascii_digit)
+            || segment.len() >= 16 && !segment.contains(&b'.'))
+}
+
+/// Identifies an origin tracker by its normalized announce URL: lowercase scheme and host, the
+/// port made explicit, and the path without trailing slash. The query is dropped since it carries
+/// passkeys, and so are path segments that may be one, so that the key never contains them.
+pub fn tracker_key(url: &Url) -> String {
+    let mut key = format!(
+        "{}://{}",
+        url.scheme(),
+        url.host_str().unwrap_or_default().to_lowercase()
+    );
+    if let Some(port) = url.port_or_known_default() {
+        key += &format!(":{port}");
+    }
+    for segment in url.path_segments().into_iter().flatten() {
+        if segment.is_empty() {
+            continue;
+        }
+        key.push('/');
+        if is_secret_segment(segment.as_bytes()) {
+            key.push('*');
+        } else {
+            key += segment;
+        }
+    }
+    key
+}
+
+pub fn deserialize_peers_binary(value: &[u8]) -> Vec<SocketAddr> {
     debug_assert_eq!(value.len() % 6, 0);
     value
         .chunks(6)
@@ -76,24 +246,24 @@ pub(crate) fn deserialize_peers_binary(value: &[u8]) -> Vec<SocketAddr> {
         .collect()
 }
 
-pub(crate) fn serialize_peer_binary(value: &SocketAddr) -> Vec<u8> {
+pub fn serialize_peer_binary(value: &SocketAddr) -> Vec<u8> {
     match value.ip() {
         IpAddr::V4(addr) => addr
-            .as_octets()
-            .into_iter()
+            .octets()
+            .iter()
             .chain(value.port().to_be_bytes().iter())
             .copied()
             .collect(),
         IpAddr::V6(addr) => addr
-            .as_octets()
-            .into_iter()
+            .octets()
+            .iter()
             .chain(value.port().to_be_bytes().iter())
             .copied()
             .collect(),
     }
 }
 
-pub(crate) fn deserialize_peers6_binary(value: &[u8]) -> Vec<SocketAddr> {
+pub fn deserialize_peers6_binary(value: &[u8]) -> Vec<SocketAddr> {
     debug_assert_eq!(value.len() % 18, 0);
     value
         .chunks(18)
@@ -107,14 +277,18 @@ pub(crate) fn deserialize_peers6_binary(value: &[u8]) -> Vec<SocketAddr> {
         .collect()
 }
 
+/// When an origin tracker last responded to [`announce`] without a failure reason.
+static LAST_RESPONSE: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+/// When an origin tracker last responded with a valid announce response, if ever since startup.
+pub fn last_response() -> Option<SystemTime> {
+    *LAST_RESPONSE.lock().unwrap()
+}
+
 /// Announce to the origin tracker. A fixed fake qBittorrent client fingerprint generated from the
 /// tracker URL is used as a disguise. To construct a realistic request, the torrent size must be
-/// known at this moment.
-pub(crate) async fn announce(
-    tracker_url: &str,
-    info_hash: &[u8],
-    size: u64,
-) -> Result<AnnounceResponse> {
+/// known at this moment. Connections are pooled per client, see [`client`].
+pub async fn announce(tracker_url: &str, info_hash: &[u8], size: u64) -> Result<AnnounceResponse> {
     let url = Url::parse(tracker_url)?;
     let info_hash_encoded = percent_encode(info_hash, NON_ALPHANUMERIC).to_string();
     // We have to manually concatenate the URL here, because `reqwest` and `url` crate always
@@ -128,16 +302,7 @@ pub(crate) async fn announce(
         format!("{tracker_url}/?info_hash={info_hash_encoded}")
     })?;
 
-    let http_client = Client::builder()
-        .user_agent(&random_client_ua(tracker_url))
-        .gzip(true);
-
-    let http_client = if let Ok(proxy_url) = std::env::var("PROXY") {
-        http_client.proxy(Proxy::all(&proxy_url)?)
-    } else {
-        http_client
-    }
-    .build()?;
+    let http_client = get_client(&ClientSettings::for_tracker(tracker_url)?)?;
 
     let req = http_client
         .request(Method::GET, url)
@@ -156,13 +321,132 @@ pub(crate) async fn announce(
             ("supportcrypto", "1"),
             ("redundant", "0"),
         ])
-        .header(reqwest::header::CONNECTION, "close")
+        .timeout(get_request_timeout())
         .build()?;
-    eprintln!("{:#?}", req);
+    debug!(tracker = %tracker_key(req.url()), "announcing to origin");
+    trace!(url = %req.url(), "announce request");
 
     let response = http_client.execute(req).await?;
-    eprintln!("{:#?}", response);
+    debug!(status = %response.status(), "origin responded");
     let response_bytes = response.bytes().await?;
 
-    bt_bencode::from_slice(&response_bytes).map_err(|e| anyhow::anyhow!(e))
+    let response: AnnounceResponse =
+        bt_bencode::from_slice(&response_bytes).map_err(|e| anyhow::anyhow!(e))?;
+    if response.failure_reason.is_none() {
+        *LAST_RESPONSE.lock().unwrap() = Some(SystemTime::now());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{AnnounceResponse, ScrapeFile, ScrapeResponse, get_raw_query_params, tracker_key};
+
+    fn key(url: &str) -> String {
+        tracker_key(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn normalizes_tracker_urls() {
+        assert_eq!(
+            key("HTTPS://Tracker.Example.com/announce?passkey=0123"),
+            "https://tracker.example.com:443/announce"
+        );
+        assert_eq!(
+            key("http://tracker.example.com:80/announce/"),
+            "http://tracker.example.com:80/announce"
+        );
+        assert_eq!(
+            key("udp://Tracker.Example.com:6969"),
+            "udp://tracker.example.com:6969"
+        );
+        assert_eq!(
+            key("https://tracker.example.com/0123456789abcdef0123/announce"),
+            "https://tracker.example.com:443/*/announce"
+        );
+        assert_eq!(
+            key("https://tracker.example.com/announce/123e4567-e89b-12d3-a456-426614174000"),
+            "https://tracker.example.com:443/announce/*"
+        );
+        assert_eq!(
+            key("https://tracker.example.com/tr/a1b2c3d4/announce.php"),
+            "https://tracker.example.com:443/tr/*/announce.php"
+        );
+        assert_eq!(
+            key("https://tracker.example.com/aBcDeFgHiJkLmNoP_qRsT/announce"),
+            "https://tracker.example.com:443/*/announce"
+        );
+    }
+
+    #[test]
+    fn distinguishes_trackers_on_one_host() {
+        let keys = [
+            key("https://example.com/a/announce"),
+            key("https://example.com/b/announce"),
+            key("https://example.com:8443/a/announce"),
+            key("udp://example.com:443/a/announce"),
+        ];
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn recognizes_gone_torrents() {
+        let reason = |x: &str| AnnounceResponse::failure(x.to_string()).reports_torrent_gone();
+        assert!(reason("Torrent not registered with this tracker"));
+        assert!(reason("Unregistered torrent"));
+        assert!(reason("Unknown torrent"));
+        assert!(reason("torrent does not exist"));
+        assert!(reason("Torrent has been deleted"));
+        assert!(!reason("Passkey not found"));
+        assert!(!reason("Invalid passkey, torrent not registered"));
+        assert!(!reason("User does not exist"));
+        assert!(!reason("Client not registered"));
+        assert!(!reason("Account deleted"));
+        assert!(!reason("Not found"));
+    }
+
+    #[test]
+    fn encodes_scrapes() {
+        let info_hashes: Vec<_> =
+            get_raw_query_params("info_hash=ab&peer_id=x&info_hash=%00c", "info_hash").collect();
+        assert_eq!(
+            info_hashes,
+            [b"ab".as_slice().into(), b"\0c".as_slice().into()]
+        );
+
+        let mut response = ScrapeResponse::default();
+        response.files.insert(
+            info_hashes[0].to_vec().into(),
+            ScrapeFile {
+                complete: 0,
+                downloaded: 2,
+                incomplete: 5,
+            },
+        );
+        assert_eq!(
+            bt_bencode::to_vec(&response).unwrap(),
+            b"d5:filesd2:abd8:completei0e10:downloadedi2e10:incompletei5eeee"
+        );
+    }
+
+    #[test]
+    fn accepts_final_responses() {
+        let reason = |x: &str| AnnounceResponse::failure(x.to_string()).accepted();
+        assert!(reason("Torrent not registered with this tracker").is_ok());
+        assert_eq!(
+            reason("Rate limited, try again later")
+                .unwrap_err()
+                .to_string(),
+            "origin tracker refused the announce: Rate limited, try again later"
+        );
+        let mut response = AnnounceResponse::failure(String::new());
+        response.failure_reason = None;
+        assert!(response.accepted().is_ok());
+    }
 }
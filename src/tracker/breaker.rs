@@ -0,0 +1,236 @@
+//! Per-tracker circuit breakers.
+//!
+//! A tracker that keeps failing is not contacted again until a cooldown has passed. After that,
+//! a single request is let through as a probe: if it succeeds the circuit closes, otherwise it
+//! opens for another cooldown. Meanwhile callers are expected to serve what they have cached.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe is in flight. Should it never report back, e.g. because it was cancelled, another
+    /// one is let through after `until`.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+/// The error of requests rejected by an open circuit.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub tracker: String,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "origin tracker {} is unavailable, try again later",
+            self.tracker
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// The state of a circuit, as reported to operators.
+#[derive(PartialEq, Eq, Debug)]
+pub enum CircuitState {
+    /// Still contacted, after this many consecutive failures.
+    Closed { failures: u32 },
+    /// Not contacted until a probe is let through after `remaining`.
+    Open { remaining: Duration },
+    /// A probe is in flight.
+    HalfOpen,
+}
+
+pub struct CircuitBreakers {
+    threshold: u32,
+    cooldown: Duration,
+    states: Mutex<HashMap<String, State>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            states: Default::default(),
+        }
+    }
+
+    /// Asks for permission to contact `tracker`. Once the cooldown is over, exactly one caller is
+    /// allowed through until its outcome is recorded.
+    pub fn check(&self, tracker: &str, now: Instant) -> Result<(), CircuitOpen> {
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(tracker) else {
+            return Ok(());
+        };
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } | State::HalfOpen { until } if now >= until => {
+                *state = State::HalfOpen {
+                    until: now + self.cooldown,
+                };
+                Ok(())
+            }
+            State::Open { .. } | State::HalfOpen { .. } => Err(CircuitOpen {
+                tracker: tracker.to_string(),
+            }),
+        }
+    }
+
+    /// Records the final outcome of contacting `tracker`, after any retries.
+    pub fn record<T, E>(&self, tracker: &str, result: &Result<T, E>, now: Instant) {
+        match result {
+            Ok(_) => self.record_success(tracker),
+            Err(_) => self.record_failure(tracker, now),
+        }
+    }
+
+    pub fn record_success(&self, tracker: &str) {
+        self.states.lock().unwrap().remove(tracker);
+    }
+
+    /// Every tracker which failed since it last succeeded, sorted.
+    pub fn states(&self, now: Instant) -> Vec<(String, CircuitState)> {
+        let mut states: Vec<_> = self
+            .states
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tracker, state)| {
+                let state = match *state {
+                    State::Closed { failures } => CircuitState::Closed { failures },
+                    State::Open { until } => CircuitState::Open {
+                        remaining: until.saturating_duration_since(now),
+                    },
+                    State::HalfOpen { .. } => CircuitState::HalfOpen,
+                };
+                (tracker.clone(), state)
+            })
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    pub fn record_failure(&self, tracker: &str, now: Instant) {
+        let mut states = self.states.lock().unwrap();
+        let state = states
+            .entry(tracker.to_string())
+            .or_insert(State::Closed { failures: 0 });
+        *state = match *state {
+            State::Closed { failures } if failures + 1 < self.threshold => State::Closed {
+                failures: failures + 1,
+            },
+            _ => State::Open {
+                until: now + self.cooldown,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{CircuitBreakers, CircuitState};
+    use crate::tracker::AnnounceResponse;
+
+    #[test]
+    fn opens_after_threshold() {
+        let breakers = CircuitBreakers::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..2 {
+            breakers.record_failure("a", now);
+            assert!(breakers.check("a", now).is_ok());
+        }
+        breakers.record_failure("a", now);
+        assert!(breakers.check("a", now).is_err());
+        assert!(breakers.check("b", now).is_ok());
+    }
+
+    #[test]
+    fn reports_states() {
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        breakers.record_failure("b", now);
+        breakers.record_failure("a", now);
+        breakers.record_failure("a", now);
+        let later = now + Duration::from_secs(20);
+        assert_eq!(
+            breakers.states(later),
+            [
+                (
+                    "a".to_string(),
+                    CircuitState::Open {
+                        remaining: Duration::from_secs(40)
+                    }
+                ),
+                ("b".to_string(), CircuitState::Closed { failures: 1 }),
+            ]
+        );
+        breakers.record_success("b");
+        assert_eq!(breakers.states(later).len(), 1);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        breakers.record_failure("a", now);
+        breakers.record_success("a");
+        breakers.record_failure("a", now);
+        assert!(breakers.check("a", now).is_ok());
+    }
+
+    #[test]
+    fn counts_refused_announces_as_failures() {
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let refused = AnnounceResponse::failure("Invalid passkey".to_string()).accepted();
+        breakers.record("a", &refused, now);
+        assert_eq!(
+            breakers.states(now),
+            [("a".to_string(), CircuitState::Closed { failures: 1 })]
+        );
+        let gone = AnnounceResponse::failure("Unregistered torrent".to_string()).accepted();
+        breakers.record("a", &gone, now);
+        assert!(breakers.states(now).is_empty());
+    }
+
+    #[test]
+    fn half_opens_for_a_single_probe() {
+        let breakers = CircuitBreakers::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        breakers.record_failure("a", now);
+        let later = now + Duration::from_secs(61);
+        assert!(breakers.check("a", later).is_ok());
+        assert!(breakers.check("a", later).is_err());
+
+        // A probe that never reports back is eventually replaced.
+        let later = later + Duration::from_secs(61);
+        assert!(breakers.check("a", later).is_ok());
+        assert!(breakers.check("a", later).is_err());
+
+        // A failed probe opens the circuit again.
+        breakers.record_failure("a", later);
+        assert!(breakers.check("a", later + Duration::from_secs(1)).is_err());
+
+        // A successful one closes it.
+        let even_later = later + Duration::from_secs(61);
+        assert!(breakers.check("a", even_later).is_ok());
+        breakers.record_success("a");
+        assert!(breakers.check("a", even_later).is_ok());
+        assert!(breakers.check("a", even_later).is_ok());
+    }
+}
@@ -0,0 +1,92 @@
+//! Pooled HTTP clients for origin trackers.
+//!
+//! Building a [`Client`] is expensive, and each one owns its connection pool, so a client per
+//! announce would never reuse a connection or TLS session. Instead, one client is built for each
+//! distinct set of settings and kept for the lifetime of the process.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use reqwest::{Client, Proxy};
+use url::Url;
+
+use super::proxy::proxy_rules;
+use crate::{config::config, utils::random_client_ua};
+
+/// Everything a client is built from. Trackers sharing the same settings share a client.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub(crate) struct ClientSettings {
+    /// The disguise of the client, see [`random_client_ua`].
+    pub(crate) user_agent: String,
+    pub(crate) proxy: Option<String>,
+}
+
+impl ClientSettings {
+    /// The settings for the tracker at `tracker_url`.
+    pub(crate) fn for_tracker(tracker_url: &str) -> Result<Self> {
+        let url = Url::parse(tracker_url)?;
+        Ok(Self {
+            user_agent: random_client_ua(tracker_url),
+            proxy: proxy_rules()
+                .route(url.host_str().unwrap_or_default())
+                .map(str::to_string),
+        })
+    }
+}
+
+static CLIENTS: LazyLock<Mutex<HashMap<ClientSettings, Client>>> = LazyLock::new(Default::default);
+
+pub(crate) fn get_request_timeout() -> Duration {
+    Duration::from_secs(config().origin.timeout)
+}
+
+/// How long an idle connection to origin is kept open for reuse. Zero disables reuse.
+fn get_keepalive() -> Duration {
+    Duration::from_secs(config().origin.keepalive)
+}
+
+/// Returns the client for `settings`, building it on first use. Clients are cheap to clone, as
+/// clones share the same pool.
+pub(crate) fn get_client(settings: &ClientSettings) -> Result<Client> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(settings) {
+        return Ok(client.clone());
+    }
+
+    let client = build_client(settings)?;
+    clients.insert(settings.clone(), client.clone());
+    Ok(client)
+}
+
+fn build_client(settings: &ClientSettings) -> Result<Client> {
+    let keepalive = get_keepalive();
+    let mut builder = Client::builder()
+        .user_agent(&settings.user_agent)
+        .gzip(true)
+        .connect_timeout(get_request_timeout())
+        .pool_idle_timeout(keepalive);
+    if keepalive.is_zero() {
+        builder = builder.pool_max_idle_per_host(0);
+    }
+    if let Some(proxy) = &settings.proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Builds a client for every route to origin trackers, i.e. directly or through each of the
+/// configured proxies, to tell whether announces can be sent at all. The clients are thrown away,
+/// since their user agent is not that of any tracker.
+pub fn check_clients() -> Result<()> {
+    for proxy in proxy_rules().routes() {
+        build_client(&ClientSettings {
+            user_agent: random_client_ua(""),
+            proxy: proxy.map(str::to_string),
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+//! Limits on outbound announces to origin trackers.
+//!
+//! Every announce needs a connection slot of its tracker host, a token from the rate limiter of
+//! that host, and a slot of the global cap, taken in that order so that waiting for a busy host
+//! never holds on to a global slot. Giving up on a wait yields [`Throttled`], which callers may
+//! answer with whatever they have cached.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit},
+    time::{Instant, sleep, timeout},
+};
+
+/// The error of an announce that could not start in time.
+#[derive(Debug)]
+pub struct Throttled {
+    pub host: String,
+}
+
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many announces to {} in progress", self.host)
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct HostLimit {
+    connections: Arc<Semaphore>,
+    bucket: Mutex<Bucket>,
+}
+
+pub struct OriginLimits {
+    global: Semaphore,
+    host_connections: usize,
+    /// Tokens per second, or zero if unlimited.
+    host_rate: f64,
+    host_burst: f64,
+    hosts: Mutex<HashMap<String, Arc<HostLimit>>>,
+}
+
+/// Allows announcing until dropped.
+pub struct OriginPermit<'a> {
+    _host: OwnedSemaphorePermit,
+    _global: SemaphorePermit<'a>,
+}
+
+impl OriginLimits {
+    pub fn new(
+        global_connections: usize,
+        host_connections: usize,
+        host_rate: f64,
+        host_burst: u32,
+    ) -> Self {
+        Self {
+            global: Semaphore::new(global_connections),
+            host_connections,
+            host_rate,
+            host_burst: host_burst.max(1) as f64,
+            hosts: Default::default(),
+        }
+    }
+
+    fn host(&self, host: &str) -> Arc<HostLimit> {
+        let mut hosts = self.hosts.lock().unwrap();
+        // Tracker URLs come from clients, so forget idle hosts rather than growing forever. A
+        // host is idle if nobody holds or waits for it, and its bucket has refilled.
+        if hosts.len() >= 1024 {
+            let now = Instant::now();
+            hosts.retain(|_, limit| {
+                Arc::strong_count(limit) > 1
+                    || limit.connections.available_permits() < self.host_connections
+                    || self.refill(&mut limit.bucket.lock().unwrap(), now) < self.host_burst
+            });
+        }
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                Arc::new(HostLimit {
+                    connections: Arc::new(Semaphore::new(self.host_connections)),
+                    bucket: Mutex::new(Bucket {
+                        tokens: self.host_burst,
+                        updated: Instant::now(),
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.host_rate).min(self.host_burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+
+    /// Waits for a token of `limit`.
+    async fn take_token(&self, limit: &HostLimit) {
+        if self.host_rate <= 0. {
+            return;
+        }
+        loop {
+            let wait = {
+                let mut bucket = limit.bucket.lock().unwrap();
+                if self.refill(&mut bucket, Instant::now()) >= 1. {
+                    bucket.tokens -= 1.;
+                    return;
+                }
+                Duration::from_secs_f64((1. - bucket.tokens) / self.host_rate)
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Waits up to `patience` for permission to announce to a tracker at `host`.
+    pub async fn acquire(&self, host: &str, patience: Duration) -> Result<OriginPermit<'_>> {
+        let limit = self.host(host);
+        let acquire = async {
+            let host_permit = limit.connections.clone().acquire_owned().await?;
+            self.take_token(&limit).await;
+            Ok::<_, anyhow::Error>(OriginPermit {
+                _host: host_permit,
+                _global: self.global.acquire().await?,
+            })
+        };
+        timeout(patience, acquire).await.map_err(|_| Throttled {
+            host: host.to_string(),
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{OriginLimits, Throttled};
+
+    const SHORT: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn limits_connections_per_host() {
+        let limits = OriginLimits::new(10, 1, 0., 1);
+        let _a = limits.acquire("a", SHORT).await.unwrap();
+        let e = limits.acquire("a", SHORT).await.err().unwrap();
+        assert!(e.is::<Throttled>());
+        assert!(limits.acquire("b", SHORT).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_connections_globally() {
+        let limits = OriginLimits::new(1, 10, 0., 1);
+        let a = limits.acquire("a", SHORT).await.unwrap();
+        assert!(limits.acquire("b", SHORT).await.is_err());
+        drop(a);
+        assert!(limits.acquire("b", SHORT).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_rate_per_host() {
+        let limits = OriginLimits::new(10, 10, 20., 2);
+        for _ in 0..2 {
+            assert!(limits.acquire("a", SHORT).await.is_ok());
+        }
+        assert!(limits.acquire("a", SHORT).await.is_err());
+        assert!(limits.acquire("b", SHORT).await.is_ok());
+        // The next token arrives after 50ms.
+        assert!(
+            limits
+                .acquire("a", Duration::from_millis(100))
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Routing of origin trackers through proxies.
+//!
+//! Rules are tried in order and the first one matching the tracker host decides whether it is
+//! contacted directly or through a proxy. A pattern containing `*`, `?` or `[` is a glob over the
+//! whole host, anything else matches the host itself and all of its subdomains. Trackers matching
+//! no rule go through the fallback proxy if set, or directly otherwise.
+
+use std::sync::LazyLock;
+
+use anyhow::{Context as _, Result};
+use globset::{Glob, GlobMatcher};
+use url::Url;
+
+use crate::config::{ProxyRule, config};
+
+enum HostPattern {
+    Glob(GlobMatcher),
+    Suffix(String),
+}
+
+impl HostPattern {
+    fn parse(value: &str) -> Result<Self> {
+        let value = value.to_lowercase();
+        if value.contains(['*', '?', '[']) {
+            Ok(Self::Glob(Glob::new(&value)?.compile_matcher()))
+        } else {
+            Ok(Self::Suffix(value.trim_start_matches('.').to_string()))
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Glob(glob) => glob.is_match(host),
+            Self::Suffix(suffix) => {
+                host == suffix
+                    || host
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|x| x.ends_with('.'))
+            }
+        }
+    }
+}
+
+/// The proxy URL to go through, or `None` to connect directly.
+fn parse_route(value: &str) -> Result<Option<String>> {
+    if value == "direct" {
+        return Ok(None);
+    }
+    let url = Url::parse(value).with_context(|| format!("invalid proxy URL {value:?}"))?;
+    if !["http", "https", "socks5", "socks5h"].contains(&url.scheme()) {
+        return Err(anyhow::anyhow!(
+            "unsupported proxy scheme {:?} in {:?}",
+            url.scheme(),
+            value
+        ));
+    }
+    Ok(Some(value.to_string()))
+}
+
+pub struct ProxyRules {
+    rules: Vec<(HostPattern, Option<String>)>,
+    fallback: Option<String>,
+}
+
+impl ProxyRules {
+    pub fn new(rules: &[ProxyRule], fallback: Option<&str>) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok((
+                    HostPattern::parse(&rule.host)
+                        .with_context(|| format!("invalid proxy rule host {:?}", rule.host))?,
+                    parse_route(&rule.route)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            rules,
+            fallback: fallback.map(parse_route).transpose()?.flatten(),
+        })
+    }
+
+    /// The proxy for trackers at `host`, or `None` to connect directly.
+    pub fn route(&self, host: &str) -> Option<&str> {
+        let host = host.to_lowercase();
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&host))
+            .map_or(&self.fallback, |(_, route)| route)
+            .as_deref()
+    }
+
+    /// Every route in use, with `None` for connecting directly. Routes may be repeated.
+    pub fn routes(&self) -> impl Iterator<Item = Option<&str>> {
+        self.rules
+            .iter()
+            .map(|(_, route)| route)
+            .chain([&self.fallback])
+            .map(Option::as_deref)
+    }
+}
+
+/// The routing table of the configuration, which was validated at startup.
+static PROXY_RULES: LazyLock<ProxyRules> = LazyLock::new(|| {
+    let origin = &config().origin;
+    ProxyRules::new(&origin.proxy_rules, origin.proxy.as_deref()).expect("invalid proxy rules")
+});
+
+pub(crate) fn proxy_rules() -> &'static ProxyRules {
+    &PROXY_RULES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProxyRules;
+    use crate::config::ProxyRule;
+
+    fn rules(rules: &[(&str, &str)], fallback: Option<&str>) -> anyhow::Result<ProxyRules> {
+        let rules: Vec<_> = rules
+            .iter()
+            .map(|&(host, route)| ProxyRule {
+                host: host.to_string(),
+                route: route.to_string(),
+            })
+            .collect();
+        ProxyRules::new(&rules, fallback)
+    }
+
+    #[test]
+    fn routes_by_first_matching_rule() {
+        let rules = rules(
+            &[
+                ("direct.example.com", "direct"),
+                ("*.example.com", "socks5://127.0.0.1:1080"),
+                (".example.org", "http://proxy:8080"),
+            ],
+            Some("http://fallback:3128"),
+        )
+        .unwrap();
+        assert_eq!(rules.route("Direct.Example.com"), None);
+        assert_eq!(
+            rules.route("tracker.example.com"),
+            Some("socks5://127.0.0.1:1080")
+        );
+        assert_eq!(rules.route("example.org"), Some("http://proxy:8080"));
+        assert_eq!(rules.route("a.b.example.org"), Some("http://proxy:8080"));
+        assert_eq!(rules.route("notexample.org"), Some("http://fallback:3128"));
+        assert_eq!(rules.route("example.com"), Some("http://fallback:3128"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(rules(&[("example.com", "ftp://proxy")], None).is_err());
+        assert!(rules(&[("[", "direct")], None).is_err());
+        assert!(rules(&[], Some("not a url")).is_err());
+        assert_eq!(rules(&[], None).unwrap().route("a"), None);
+    }
+}
@@ -1,11 +1,7 @@
 //! https://github.com/lyc8503/PTHackPoC/blob/79dbeba76b24a445eedddb4fcdba7ef06305cb6f/util/util.go#L15
 
-use anyhow::Result;
 use rand::{RngCore as _, SeedableRng as _, rngs::StdRng};
 use sha2::Digest as _;
-use url::Url;
-
-use crate::bytes_bencode::BencodeObject;
 
 const QB_VERSIONS: [&str; 8] = [
     "-qB5120-", "-qB5110-", "-qB5100-", "-qB5050-", "-qB5040-", "-qB5030-", "-qB5020-", "-qB5010-",
@@ -68,74 +64,7 @@ pub(crate) fn random_key(value: &str) -> String {
     key
 }
 
-pub(crate) unsafe fn as_array_ref<'a, const N: usize>(slice: &'a [u8]) -> &'a [u8; N] {
+pub(crate) unsafe fn as_array_ref<const N: usize>(slice: &[u8]) -> &[u8; N] {
     debug_assert!(slice.len() >= N);
     unsafe { &*(slice.as_ptr() as *const [u8; N]) }
 }
-
-#[inline]
-fn process_tracker_url(value: &str) -> Result<String> {
-    let tracker_base_url =
-        std::env::var("BASE_URL").unwrap_or("https://tracker.submy.org".to_string());
-
-    let mut url = Url::parse(&tracker_base_url)?.join("announce")?;
-    url.query_pairs_mut()
-        .append_pair("tracker_url", value)
-        .append_pair("ttl", "28800");
-    Ok(url.to_string())
-}
-
-pub(crate) fn replace_trackers_in_torrent(torrent: &mut BencodeObject) -> Result<Box<[Box<[u8]>]>> {
-    const ANNOUNCE_LITERAL: &[u8] = "announce".as_bytes();
-    const ANNOUNCE_LIST_LITERAL: &[u8] = "announce-list".as_bytes();
-
-    let mut result = Vec::new();
-
-    if let BencodeObject::List(obj) = torrent {
-        if let BencodeObject::Dictionary(obj) = obj.first_mut().unwrap() {
-            for (k, v) in obj {
-                if let BencodeObject::Bytes(len, obj) = k
-                    && *len != 0
-                {
-                    if obj.as_ref().unwrap() == ANNOUNCE_LITERAL
-                        && let BencodeObject::Bytes(len, obj) = v
-                        && *len != 0
-                    {
-                        let obj = obj.as_mut().unwrap();
-                        let new_url =
-                            process_tracker_url(&String::from_utf8(std::mem::take(obj))?)?
-                                .into_bytes();
-                        *len = new_url.len();
-                        result.push(std::mem::replace(obj, new_url).into_boxed_slice());
-                    } else if obj.as_ref().unwrap() == ANNOUNCE_LIST_LITERAL
-                        && let BencodeObject::List(obj) = v
-                    {
-                        for obj in obj {
-                            if let BencodeObject::List(obj) = obj {
-                                for obj in obj {
-                                    if let BencodeObject::Bytes(len, obj) = obj
-                                        && *len != 0
-                                    {
-                                        let obj = obj.as_mut().unwrap();
-                                        let new_url = process_tracker_url(&String::from_utf8(
-                                            std::mem::take(obj),
-                                        )?)?
-                                        .into_bytes();
-                                        *len = new_url.len();
-                                        result.push(
-                                            std::mem::replace(obj, new_url).into_boxed_slice(),
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    // no need to sort, since "announce" and "announce-list" are adjacent to each other
-    result.dedup();
-    Ok(result.into_boxed_slice())
-}